@@ -0,0 +1,100 @@
+//! Copies of recordings, kept next to the chat log so a wrong transcription can be checked
+//! against what the microphone actually heard.
+
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use crate::audio::{self, Clip};
+use crate::chatlog;
+use crate::config::{self, UploadFormat};
+
+pub fn dir() -> anyhow::Result<PathBuf> {
+    Ok(chatlog::data_dir()?.join("recordings"))
+}
+
+/// Save a recording and make room for it. Returns the id to log it under, or `None` when the
+/// archive is turned off.
+pub fn store(clip: &Clip, archive: &config::Archive) -> anyhow::Result<Option<String>> {
+    if !archive.enabled {
+        return Ok(None);
+    }
+    let dir = dir()?;
+    std::fs::create_dir_all(&dir)?;
+
+    let id = chrono::Local::now().format("%Y%m%d-%H%M%S%.3f").to_string();
+    // lossless, and half the size of WAV
+    let encoded = audio::encode(clip, UploadFormat::Flac)?;
+    let path = dir.join(format!("{id}.flac"));
+    std::fs::write(&path, encoded.bytes)?;
+
+    rotate(
+        &dir,
+        &path,
+        archive.max_size_mb * 1_000_000,
+        archive.max_age(),
+    )?;
+    Ok(Some(id))
+}
+
+/// Delete recordings older than `max_age`, then the oldest of the rest until they fit in
+/// `max_bytes`. The recording at `keep` was just made and the log is about to name it, so it
+/// stays whatever the limits are. Files that aren't recordings are left alone.
+fn rotate(dir: &Path, keep: &Path, max_bytes: u64, max_age: Duration) -> anyhow::Result<()> {
+    let now = SystemTime::now();
+    let mut recordings = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let metadata = entry.metadata()?;
+        if metadata.is_file() && path.extension().is_some_and(|ext| ext == "flac") {
+            recordings.push((metadata.modified()?, metadata.len(), path));
+        }
+    }
+    // newest first, so whatever is past the limits is at the end
+    recordings.sort_by_key(|&(modified, _, _)| std::cmp::Reverse(modified));
+
+    let mut kept_bytes = 0;
+    for (modified, len, path) in recordings {
+        let age = now.duration_since(modified).unwrap_or_default();
+        kept_bytes += len;
+        if path != keep && (age > max_age || kept_bytes > max_bytes) {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotation_drops_the_oldest_past_the_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["a.flac", "b.flac", "c.flac"] {
+            std::fs::write(dir.path().join(name), [0; 100]).unwrap();
+            // modification times are only so fine grained
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        let remaining = || {
+            let mut names: Vec<_> = std::fs::read_dir(dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            names.sort();
+            names
+        };
+
+        std::fs::write(dir.path().join("notes.txt"), [0; 1000]).unwrap();
+        let newest = dir.path().join("c.flac");
+
+        rotate(dir.path(), &newest, 250, Duration::from_secs(60)).unwrap();
+        assert_eq!(remaining(), ["b.flac", "c.flac", "notes.txt"]);
+
+        // the newest recording is kept even when it is past the limits on its own
+        rotate(dir.path(), &newest, 0, Duration::ZERO).unwrap();
+        assert_eq!(remaining(), ["c.flac", "notes.txt"]);
+    }
+}
@@ -1,25 +1,201 @@
 use std::{
-    collections::VecDeque,
-    sync::{mpsc, Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use hound::{WavSpec, WavWriter};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
 
-use crate::consts::POLL_INTERVAL;
+use crate::config::{self, RecordMode, UploadFormat};
 use crate::Button;
 
-fn to_wav(audio_data_f32: Vec<f32>, config: &cpal::StreamConfig) -> Vec<u8> {
-    // Convert f32 samples to i16
-    let audio_data_i16: Vec<i16> = audio_data_f32
+mod decode;
+mod devices;
+mod earcon;
+mod file;
+mod flac;
+mod gain;
+mod hardware;
+mod opus;
+mod resample;
+mod vad;
+mod wake_word;
+
+pub use decode::decode;
+pub use devices::list as list_devices;
+pub use earcon::{Earcon, Earcons};
+pub use gain::Level;
+pub use hardware::Engine;
+pub use wake_word::WakeWord;
+
+use file::{NullSink, WavFileSink, WavFileSource};
+use hardware::{DeviceSink, DeviceSource};
+use vad::{Vad, Verdict};
+
+/// Interleaved audio along with the format needed to make sense of it.
+#[derive(Clone)]
+pub struct Clip {
+    pub samples: Vec<f32>,
+    pub channels: u16,
+    pub sample_rate: u32,
+}
+
+impl Clip {
+    pub fn duration(&self) -> Duration {
+        let frames = self.samples.len() / self.channels.max(1) as usize;
+        Duration::from_secs_f64(frames as f64 / self.sample_rate as f64)
+    }
+
+    /// RMS level of the loudest 50 ms, in dBFS.
+    pub fn loudest_db(&self) -> f32 {
+        let block = (self.sample_rate as usize / 20 * self.channels as usize).max(1);
+        let loudest = self
+            .samples
+            .chunks(block)
+            .map(|b| b.iter().map(|s| s * s).sum::<f32>() / b.len() as f32)
+            .fold(0.0, f32::max);
+        10.0 * loudest.max(f32::MIN_POSITIVE).log10()
+    }
+
+    /// Peak, RMS and clipping statistics.
+    pub fn stats(&self) -> InputStats {
+        let peak = self
+            .samples
+            .iter()
+            .fold(0.0f32, |peak, s| peak.max(s.abs()));
+        let power =
+            self.samples.iter().map(|s| s * s).sum::<f32>() / self.samples.len().max(1) as f32;
+        InputStats {
+            peak_db: 20.0 * peak.max(f32::MIN_POSITIVE).log10(),
+            rms_db: 10.0 * power.max(f32::MIN_POSITIVE).log10(),
+            clipped_samples: self.samples.iter().filter(|s| s.abs() >= CLIPPED).count(),
+            samples: self.samples.len(),
+        }
+    }
+
+    /// Change the channel count and sample rate, resampling whichever of the two has fewer
+    /// channels.
+    pub fn convert(&self, channels: u16, sample_rate: u32) -> Clip {
+        let samples = if channels < self.channels {
+            let remixed = resample::remix(&self.samples, self.channels, channels);
+            resample::resample(&remixed, channels, self.sample_rate, sample_rate)
+        } else {
+            let resampled =
+                resample::resample(&self.samples, self.channels, self.sample_rate, sample_rate);
+            resample::remix(&resampled, self.channels, channels)
+        };
+        Clip {
+            samples,
+            channels,
+            sample_rate,
+        }
+    }
+}
+
+/// Samples this close to full scale were most likely clipped by the sound card.
+const CLIPPED: f32 = 0.999;
+
+/// Level statistics of a recording, kept with the question so a bad transcription can be told
+/// apart from a badly set microphone.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct InputStats {
+    /// dBFS
+    pub peak_db: f32,
+    /// dBFS, over the whole recording
+    pub rms_db: f32,
+    pub clipped_samples: usize,
+    pub samples: usize,
+}
+
+impl InputStats {
+    /// A warning about the microphone gain, if it looks wrong.
+    pub fn warning(&self) -> Option<String> {
+        // a few clipped samples in a shout are fine, a steady stream of them is not
+        let clipped = self.clipped_samples as f32 / self.samples.max(1) as f32;
+        if clipped > 0.001 {
+            return Some(format!(
+                "{:.2}% of the recording was clipped, the microphone gain is probably too high",
+                clipped * 100.0
+            ));
+        }
+        if self.peak_db < -30.0 {
+            return Some(format!(
+                "the recording peaked at only {:.1} dBFS, the microphone gain is probably too low",
+                self.peak_db
+            ));
+        }
+        None
+    }
+}
+
+/// Decode a WAV file of any integer or float sample format.
+pub fn from_wav(wav: &[u8]) -> anyhow::Result<Clip> {
+    let mut reader = hound::WavReader::new(std::io::Cursor::new(wav))?;
+    let spec = reader.spec();
+    let samples = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+        hound::SampleFormat::Int => {
+            let scale = (1u64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|sample| sample.map(|s| s as f32 / scale))
+                .collect::<Result<_, _>>()?
+        }
+    };
+    Ok(Clip {
+        samples,
+        channels: spec.channels,
+        sample_rate: spec.sample_rate,
+    })
+}
+
+/// A recording encoded for upload, with the file name and MIME type that identify its format.
+pub struct Encoded {
+    pub bytes: Vec<u8>,
+    pub file_name: &'static str,
+    pub mime_type: &'static str,
+}
+
+pub fn encode(clip: &Clip, format: UploadFormat) -> anyhow::Result<Encoded> {
+    Ok(match format {
+        UploadFormat::Wav => Encoded {
+            bytes: to_wav(clip)?,
+            file_name: "audio.wav",
+            mime_type: "audio/wav",
+        },
+        UploadFormat::Flac => Encoded {
+            bytes: flac::encode(&to_i16(&clip.samples), clip.channels, clip.sample_rate)?,
+            file_name: "audio.flac",
+            mime_type: "audio/flac",
+        },
+        UploadFormat::OggOpus => Encoded {
+            bytes: opus::encode(&to_i16(&clip.samples), clip.channels, clip.sample_rate)?,
+            file_name: "audio.ogg",
+            mime_type: "audio/ogg",
+        },
+    })
+}
+
+/// Convert to 16 bit, clamping anything past full scale rather than letting it wrap around.
+fn to_i16(samples: &[f32]) -> Vec<i16> {
+    samples
         .iter()
-        .map(|sample| (sample * i16::MAX as f32) as i16)
-        .collect();
+        .map(|sample| (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16)
+        .collect()
+}
+
+pub fn to_wav(clip: &Clip) -> anyhow::Result<Vec<u8>> {
+    // Convert f32 samples to i16
+    let audio_data_i16 = to_i16(&clip.samples);
 
     // Compress the audio data to WAV
     let spec = WavSpec {
-        channels: config.channels,
-        sample_rate: config.sample_rate.0,
+        channels: clip.channels,
+        sample_rate: clip.sample_rate,
         bits_per_sample: 16,
         sample_format: hound::SampleFormat::Int,
     };
@@ -27,95 +203,373 @@ fn to_wav(audio_data_f32: Vec<f32>, config: &cpal::StreamConfig) -> Vec<u8> {
     let mut buffer = Vec::new();
     let mut cursor = std::io::Cursor::new(&mut buffer);
     {
-        let mut writer = WavWriter::new(&mut cursor, spec).unwrap();
+        let mut writer = WavWriter::new(&mut cursor, spec)?;
         for &sample in &audio_data_i16 {
-            writer.write_sample(sample).unwrap();
+            writer.write_sample(sample)?;
         }
+        writer.finalize()?;
+    }
+
+    Ok(buffer)
+}
+
+/// Where recordings come from.
+pub trait AudioSource {
+    fn channels(&self) -> u16;
+    fn sample_rate(&self) -> u32;
+    /// Start a new recording, beginning with any audio the source kept from before now if
+    /// `preroll` is set.
+    fn begin(&mut self, preroll: bool) -> Take;
+    fn end(&mut self);
+    /// Audio heard outside of recordings from now on, as it arrives, for the wake word
+    /// spotter. Sources that only produce sound while recording have nothing to offer.
+    fn listen(&mut self) -> Option<mpsc::UnboundedReceiver<Vec<f32>>> {
+        None
     }
+}
 
-    buffer
+/// A recording as a source delivers it.
+pub struct Take {
+    /// how many samples at the start were heard before the recording began
+    pub preroll: usize,
+    /// the format of the audio, which a device may change between recordings
+    pub channels: u16,
+    pub sample_rate: u32,
+    /// audio as it is recorded, closed if the source runs dry and the recording should end
+    pub chunks: mpsc::UnboundedReceiver<Vec<f32>>,
 }
 
-pub fn record_wav(button: &Button) -> Vec<u8> {
-    let host = cpal::default_host();
-    let input_device = host.default_input_device().unwrap();
+/// Where sound goes.
+pub trait AudioSink {
+    /// Start playing a clip. Playback stops when the returned [`Sound`] is dropped, or when
+    /// the clip runs out if `looped` is false.
+    fn start(&self, clip: &Clip, looped: bool) -> anyhow::Result<Sound>;
+}
 
-    let config = input_device.default_input_config().unwrap();
-    let config: cpal::StreamConfig = config.into();
+/// What started a recording.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Button,
+    WakeWord,
+}
 
-    let recording_state = Arc::new(Mutex::new(Some(Vec::new())));
-    let recording_state_clone = recording_state.clone();
+impl Trigger {
+    /// How a recording started this way ends.
+    pub fn mode(self, recording: &config::Recording) -> RecordMode {
+        match self {
+            Trigger::Button => recording.mode,
+            Trigger::WakeWord => RecordMode::HandsFree,
+        }
+    }
+}
+
+/// Records questions from whichever [`AudioSource`] is configured.
+pub struct Recorder {
+    source: Box<dyn AudioSource>,
+}
+
+impl Recorder {
+    pub fn new(audio: &config::Audio, engine: &Arc<Engine>) -> anyhow::Result<Self> {
+        let source: Box<dyn AudioSource> = match &audio.source {
+            config::Source::Device => Box::new(DeviceSource::new(engine)?),
+            config::Source::File(path) => Box::new(WavFileSource::open(path)?),
+        };
+        Ok(Self { source })
+    }
 
-    let input_stream = input_device
-        .build_input_stream(
-            &config,
-            move |data: &[f32], _: &cpal::InputCallbackInfo| {
-                let mut recording_state = recording_state_clone.lock().unwrap();
-                if let Some(ref mut recording_state) = *recording_state {
-                    recording_state.extend_from_slice(data);
+    pub fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    /// Audio heard between recordings from now on, if the source hears anything then.
+    pub fn listen(&mut self) -> Option<mpsc::UnboundedReceiver<Vec<f32>>> {
+        self.source.listen()
+    }
+
+    /// Record one question. A push-to-talk press starts with the buffered pre-roll and stops
+    /// when the button is released. Hands-free recordings, which the wake word always starts,
+    /// begin from the moment they were asked for and stop when the voice activity detector
+    /// hears the question end. They are left without pre-roll, since it would hold the wake
+    /// word or the listening chime, which push-to-talk goes without. Either way recording stops once the maximum duration is
+    /// reached.
+    pub async fn record(
+        &mut self,
+        button: &mut Button,
+        recording: &config::Recording,
+        trigger: Trigger,
+    ) -> anyhow::Result<Recorded> {
+        let mode = trigger.mode(recording);
+        button.catch_up();
+        let Take {
+            preroll,
+            channels,
+            sample_rate,
+            mut chunks,
+        } = self.source.begin(mode == RecordMode::PushToTalk);
+        let mut vad = Vad::new(&recording.vad, sample_rate, channels);
+        let mut samples = Vec::new();
+        let cut_off = tokio::time::sleep(recording.max_duration());
+        tokio::pin!(cut_off);
+        loop {
+            let chunk = tokio::select! {
+                chunk = chunks.recv() => match chunk {
+                    Some(chunk) => chunk,
+                    None => break,
+                },
+                // a closed button leaves `pressed` false, which ends the recording
+                _ = button.next(), if mode == RecordMode::PushToTalk => {
+                    if button.pressed() {
+                        continue;
+                    }
+                    // keep what was captured up to the release
+                    while let Ok(chunk) = chunks.try_recv() {
+                        samples.extend_from_slice(&chunk);
+                    }
+                    break;
                 }
+                () = &mut cut_off => {
+                    eprintln!(
+                        "recording reached the {:?} limit, cutting it off",
+                        recording.max_duration()
+                    );
+                    break;
+                }
+            };
+            samples.extend_from_slice(&chunk);
+            let done = match mode {
+                RecordMode::PushToTalk => !button.pressed(),
+                RecordMode::HandsFree => vad.push(&chunk) == Verdict::Done,
+            };
+            if done {
+                break;
+            }
+        }
+        self.source.end();
+
+        let samples_per_second = sample_rate as f64 * channels.max(1) as f64;
+        Ok(Recorded {
+            clip: Clip {
+                samples,
+                channels,
+                sample_rate,
             },
-            move |err| {
-                eprintln!("An error occurred on the input audio stream: {}", err);
-            },
-            None,
-        )
-        .unwrap();
+            preroll: Duration::from_secs_f64(preroll as f64 / samples_per_second),
+        })
+    }
+}
 
-    input_stream.play().unwrap();
+/// A recorded question.
+pub struct Recorded {
+    pub clip: Clip,
+    /// how much of the start of the clip was heard before the recording began
+    pub preroll: Duration,
+}
 
-    while button.pressed().unwrap_or(false) {
-        std::thread::sleep(POLL_INTERVAL);
+/// Why a recording isn't worth sending for transcription, if it isn't. Whisper tends to
+/// invent text for near-empty audio, so accidental taps and silence are better dropped here.
+pub fn rejection(recorded: &Recorded, recording: &config::Recording) -> Option<String> {
+    let clip = &recorded.clip;
+    let spoken = clip.duration().saturating_sub(recorded.preroll);
+    if spoken < recording.min_duration() {
+        return Some(format!(
+            "recording was too short ({spoken:?} < {:?})",
+            recording.min_duration()
+        ));
     }
+    let loudest = clip.loudest_db();
+    if loudest < recording.min_level_db {
+        return Some(format!(
+            "recording was silent (loudest {loudest:.1} dBFS < {:.1} dBFS)",
+            recording.min_level_db
+        ));
+    }
+    None
+}
 
-    let audio_data: Vec<f32> = recording_state.lock().unwrap().take().unwrap();
-    to_wav(audio_data, &config)
+/// Plays leveled sound through whichever [`AudioSink`] is configured.
+pub struct Player {
+    sink: Box<dyn AudioSink>,
 }
 
-pub fn play_wav(wav: &[u8]) -> anyhow::Result<()> {
-    let host = cpal::default_host();
-    let output_device = host
-        .default_output_device()
-        .ok_or(anyhow::anyhow!("No default output device found"))?;
+impl Player {
+    pub fn new(audio: &config::Audio, engine: &Arc<Engine>) -> anyhow::Result<Self> {
+        let sink: Box<dyn AudioSink> = match &audio.sink {
+            config::Sink::Device => Box::new(DeviceSink::new(engine)?),
+            config::Sink::File(dir) => Box::new(WavFileSink::new(dir)?),
+            config::Sink::Null => Box::new(NullSink),
+        };
+        Ok(Self { sink })
+    }
 
-    let mut reader = hound::WavReader::new(std::io::Cursor::new(wav))?;
-    let spec = reader.spec();
+    /// Play encoded audio in any format [`decode`] understands. A fresh press of the button
+    /// stops playback early, so a child doesn't have to sit through a long answer to ask
+    /// something else.
+    pub async fn play(&self, audio: &[u8], button: &mut Button) -> anyhow::Result<Playback> {
+        let mut clip = decode(audio)?;
+        Level::load()?.apply(&mut clip, true);
+        let total = clip.duration();
+        let mut sound = self.sink.start(&clip, false)?;
 
-    let config = cpal::StreamConfig {
-        channels: spec.channels,
-        sample_rate: cpal::SampleRate(spec.sample_rate),
-        buffer_size: cpal::BufferSize::Default,
-    };
+        // only a press that starts after playback began counts as an interruption
+        button.catch_up();
+        let mut open = true;
+        loop {
+            tokio::select! {
+                result = sound.done() => return result.map(|()| Playback::Finished),
+                event = button.next(), if open => match event {
+                    Some(event) if event.pressed => {
+                        return Ok(Playback::Interrupted {
+                            played: sound.played(),
+                            total,
+                        });
+                    }
+                    Some(_) => {}
+                    // nobody can interrupt anymore, so the answer plays out
+                    None => open = false,
+                },
+            }
+        }
+    }
 
-    let mut audio_data = VecDeque::new();
-    for sample in reader.samples::<i16>() {
-        audio_data.push_back(sample? as f32 / i16::MAX as f32);
+    /// Start playing a clip in the background. It stops when the returned [`Sound`] is
+    /// dropped, or when it runs out if `looped` is false.
+    pub fn start(&self, clip: &Clip, looped: bool) -> anyhow::Result<Sound> {
+        let mut clip = clip.clone();
+        Level::load()?.apply(&mut clip, false);
+        self.sink.start(&clip, looped)
     }
+}
 
-    let (tx, rx) = mpsc::channel::<anyhow::Result<()>>();
-    let tx_clone = tx.clone();
+/// A clip playing in the background. Dropping it stops playback.
+pub struct Sound {
+    done: mpsc::UnboundedReceiver<anyhow::Result<()>>,
+    /// samples handed to the device so far
+    played: Arc<AtomicUsize>,
+    samples_per_second: f64,
+    stopped: Arc<AtomicBool>,
+}
 
-    let output_stream = output_device
-        .build_output_stream(
-            &config,
-            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                for sample in data.iter_mut() {
-                    *sample = audio_data.pop_front().unwrap_or(0.0);
-                }
-                if audio_data.is_empty() {
-                    let _ = tx.send(Ok(()));
-                }
-            },
-            move |err| {
-                let _ = tx_clone.send(Err(anyhow::anyhow!(err)));
-            },
-            None,
-        )
-        .map_err(|e| anyhow::anyhow!("Failed to build audio output stream: {}", e))?;
+impl Sound {
+    /// A sound playing audio in this format, which reports how it ended on `done`.
+    fn new(
+        channels: u16,
+        sample_rate: u32,
+        done: mpsc::UnboundedReceiver<anyhow::Result<()>>,
+    ) -> Self {
+        Self {
+            done,
+            played: Arc::new(AtomicUsize::new(0)),
+            samples_per_second: sample_rate as f64 * channels as f64,
+            stopped: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// A sound that is already over, for sinks that don't play in real time.
+    fn finished(clip: &Clip) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let _ = tx.send(Ok(()));
+        let sound = Self::new(clip.channels, clip.sample_rate, rx);
+        sound.played.store(clip.samples.len(), Ordering::Relaxed);
+        sound
+    }
+
+    /// How much has been played so far.
+    pub fn played(&self) -> Duration {
+        let played = self.played.load(Ordering::Relaxed) as f64;
+        Duration::from_secs_f64(played / self.samples_per_second.max(1.0))
+    }
+
+    /// Wait until a non-looped sound has finished playing.
+    pub async fn wait(mut self) -> anyhow::Result<()> {
+        self.done().await
+    }
+
+    async fn done(&mut self) -> anyhow::Result<()> {
+        self.done.recv().await.unwrap_or_else(|| {
+            Err(anyhow::anyhow!(
+                "The audio engine stopped before the sound finished"
+            ))
+        })
+    }
+}
 
-    output_stream
-        .play()
-        .map_err(|e| anyhow::anyhow!("Failed to play audio output stream: {}", e))?;
+impl Drop for Sound {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+/// How a call to [`Player::play`] ended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Playback {
+    Finished,
+    /// The button was pressed after `played` out of `total`.
+    Interrupted {
+        played: Duration,
+        total: Duration,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overdriven_samples_clamp_and_count_as_clipped() {
+        assert_eq!(to_i16(&[1.5, -2.0, 0.5]), vec![i16::MAX, -i16::MAX, 16384]);
+
+        let clip = Clip {
+            samples: vec![1.5, -1.0, 0.0, 0.0],
+            channels: 1,
+            sample_rate: 16_000,
+        };
+        let stats = clip.stats();
+        assert_eq!(stats.clipped_samples, 2);
+        assert!(stats.warning().unwrap().contains("too high"));
 
-    rx.recv()?
+        let quiet = Clip {
+            samples: vec![0.01, -0.01],
+            ..clip
+        };
+        assert!(quiet.stats().warning().unwrap().contains("too low"));
+    }
+
+    #[test]
+    fn upmixing_copies_the_resampled_channel() {
+        let mono = Clip {
+            samples: (0..240).map(|i| (i as f32 / 10.0).sin() * 0.5).collect(),
+            channels: 1,
+            sample_rate: 24_000,
+        };
+        let stereo = mono.convert(2, 48_000);
+        assert_eq!(stereo.samples.len(), 2 * 480);
+        assert!(stereo.samples.chunks(2).all(|frame| frame[0] == frame[1]));
+        assert_eq!(
+            mono.convert(1, 48_000).samples,
+            stereo.convert(1, 48_000).samples
+        );
+    }
+
+    #[test]
+    fn only_the_preroll_attached_is_discounted() {
+        let recording = config::Recording::default();
+        let recorded = |preroll_ms| Recorded {
+            clip: Clip {
+                samples: vec![0.5; 6_400],
+                channels: 1,
+                sample_rate: 16_000,
+            },
+            preroll: Duration::from_millis(preroll_ms),
+        };
+        // 400 ms, which is long enough as a question on its own but not after 300 ms of
+        // pre-roll
+        assert!(rejection(&recorded(0), &recording).is_none());
+        assert!(rejection(&recorded(300), &recording)
+            .unwrap()
+            .contains("too short"));
+    }
 }
@@ -0,0 +1,246 @@
+//! Decoding of the compressed formats speech may arrive in. The format is told from the
+//! first few bytes, so callers don't need to know what they were sent.
+
+use std::io::Cursor;
+
+use opus_decoder::{OpusDecoder, OpusError};
+use symphonia::core::{
+    audio::SampleBuffer,
+    codecs::{self, DecoderOptions, CODEC_TYPE_OPUS},
+    errors::Error,
+    formats::{FormatOptions, Packet},
+    io::MediaSourceStream,
+    meta::MetadataOptions,
+    probe::Hint,
+};
+
+use super::{from_wav, Clip};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Wav,
+    Mp3,
+    Ogg,
+    /// AAC in ADTS frames, which is told apart only to say it isn't supported
+    Adts,
+}
+
+fn detect(bytes: &[u8]) -> Option<Format> {
+    match bytes {
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => Some(Format::Wav),
+        [b'O', b'g', b'g', b'S', ..] => Some(Format::Ogg),
+        // an ID3 tag, or straight into an MPEG audio frame sync. ADTS uses the same sync with
+        // the layer bits, which MPEG audio never leaves at zero, cleared.
+        [b'I', b'D', b'3', ..] => Some(Format::Mp3),
+        [0xff, second, ..] if second & 0xf6 == 0xf0 => Some(Format::Adts),
+        [0xff, second, ..] if second & 0xe0 == 0xe0 && second & 0x06 != 0 => Some(Format::Mp3),
+        _ => None,
+    }
+}
+
+/// Decode WAV, MP3 or Ogg Opus.
+pub fn decode(bytes: &[u8]) -> anyhow::Result<Clip> {
+    match detect(bytes) {
+        Some(Format::Wav) => from_wav(bytes),
+        Some(Format::Mp3) => decode_compressed(bytes, "mp3"),
+        Some(Format::Ogg) => decode_compressed(bytes, "ogg"),
+        Some(Format::Adts) => anyhow::bail!("Unsupported audio format AAC, use MP3 or Ogg Opus"),
+        None => anyhow::bail!(
+            "Unrecognized audio format, starting with {:02x?}",
+            &bytes[..bytes.len().min(12)]
+        ),
+    }
+}
+
+fn decode_compressed(bytes: &[u8], extension: &str) -> anyhow::Result<Clip> {
+    let source = MediaSourceStream::new(Box::new(Cursor::new(bytes.to_vec())), Default::default());
+    let mut format = symphonia::default::get_probe()
+        .format(
+            Hint::new().with_extension(extension),
+            source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )?
+        .format;
+    let track = format
+        .default_track()
+        .ok_or(anyhow::anyhow!("No audio track in {extension} data"))?;
+    let track_id = track.id;
+    let mut decoder = Decoder::new(&track.codec_params)?;
+
+    let mut samples = Vec::new();
+    let mut channels = 0;
+    let mut sample_rate = 0;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let (decoded, spec) = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // a corrupt frame is a glitch, not a reason to stay silent
+            Err(e)
+                if e.is::<OpusError>()
+                    || matches!(e.downcast_ref(), Some(Error::DecodeError(_))) =>
+            {
+                eprintln!("skipping undecodable {extension} packet: {e}");
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        (channels, sample_rate) = spec;
+        samples.extend_from_slice(&decoded);
+    }
+    anyhow::ensure!(channels > 0, "No audio in {extension} data");
+
+    Ok(Clip {
+        samples,
+        channels,
+        sample_rate,
+    })
+}
+
+/// Decodes the packets of a track. symphonia has no Opus decoder of its own.
+enum Decoder {
+    Symphonia(Box<dyn codecs::Decoder>),
+    Opus {
+        decoder: Box<OpusDecoder>,
+        channels: u16,
+        pcm: Vec<f32>,
+        /// frames still to drop from the start, which the encoder put there to warm the
+        /// decoder up
+        pre_skip: usize,
+    },
+}
+
+impl Decoder {
+    fn new(params: &codecs::CodecParameters) -> anyhow::Result<Self> {
+        if params.codec != CODEC_TYPE_OPUS {
+            let decoder =
+                symphonia::default::get_codecs().make(params, &DecoderOptions::default())?;
+            return Ok(Self::Symphonia(decoder));
+        }
+        let channels = params.channels.map_or(0, |channels| channels.count());
+        anyhow::ensure!(
+            (1..=2).contains(&channels),
+            "Unsupported Opus stream with {channels} channels, only mono and stereo can be played"
+        );
+        // Opus always decodes at 48 kHz in Ogg
+        let decoder = OpusDecoder::new(48_000, channels)?;
+        let pcm = vec![0.0; decoder.max_frame_size_per_channel() * channels];
+        Ok(Self::Opus {
+            decoder: Box::new(decoder),
+            channels: channels as u16,
+            pcm,
+            pre_skip: params.delay.unwrap_or(0) as usize,
+        })
+    }
+
+    /// The interleaved samples in a packet, and their channel count and sample rate.
+    fn decode(&mut self, packet: &Packet) -> anyhow::Result<(Vec<f32>, (u16, u32))> {
+        match self {
+            Self::Symphonia(decoder) => {
+                let decoded = decoder.decode(packet)?;
+                let spec = *decoded.spec();
+                let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+                buffer.copy_interleaved_ref(decoded);
+                let format = (spec.channels.count() as u16, spec.rate);
+                Ok((buffer.samples().to_vec(), format))
+            }
+            Self::Opus {
+                decoder,
+                channels,
+                pcm,
+                pre_skip,
+            } => {
+                let frames = decoder.decode_float(packet.buf(), pcm, false)?;
+                let skipped = frames.min(*pre_skip);
+                *pre_skip -= skipped;
+                let channels = *channels as usize;
+                let decoded = pcm[skipped * channels..frames * channels].to_vec();
+                Ok((decoded, (channels as u16, 48_000)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_formats_apart() {
+        let clip = Clip {
+            samples: vec![0.0, 0.5, -0.5],
+            channels: 1,
+            sample_rate: 24_000,
+        };
+        let wav = super::super::to_wav(&clip).unwrap();
+        assert_eq!(detect(&wav), Some(Format::Wav));
+        assert_eq!(decode(&wav).unwrap().samples.len(), 3);
+
+        assert_eq!(detect(b"ID3\x04\x00"), Some(Format::Mp3));
+        assert_eq!(detect(&[0xff, 0xf3, 0x64, 0xc4]), Some(Format::Mp3));
+        assert_eq!(detect(b"OggS\x00\x02"), Some(Format::Ogg));
+        assert_eq!(detect(&[0xff, 0xf1, 0x50, 0x80]), Some(Format::Adts));
+        assert_eq!(detect(b"fLaC"), None);
+        assert!(decode(b"hello").is_err());
+        let Err(aac) = decode(&[0xff, 0xf1, 0x50, 0x80, 0x00]) else {
+            panic!("AAC decoded");
+        };
+        assert!(aac.to_string().contains("Unsupported audio format AAC"));
+    }
+
+    /// An Ogg page holding whole packets, each shorter than 255 bytes.
+    fn ogg_page(flags: u8, granule: u64, sequence: u32, packets: &[&[u8]]) -> Vec<u8> {
+        let mut page = b"OggS\x00".to_vec();
+        page.push(flags);
+        page.extend(granule.to_le_bytes());
+        page.extend(1u32.to_le_bytes());
+        page.extend(sequence.to_le_bytes());
+        page.extend([0; 4]);
+        page.push(packets.len() as u8);
+        page.extend(packets.iter().map(|packet| packet.len() as u8));
+        for packet in packets {
+            page.extend(*packet);
+        }
+        let crc = page.iter().fold(0u32, |mut crc, &byte| {
+            crc ^= (byte as u32) << 24;
+            for _ in 0..8 {
+                crc = if crc & 0x8000_0000 != 0 {
+                    (crc << 1) ^ 0x04c1_1db7
+                } else {
+                    crc << 1
+                };
+            }
+            crc
+        });
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        page
+    }
+
+    #[test]
+    fn decodes_ogg_opus() {
+        let pre_skip = 312u16;
+        let mut head = b"OpusHead\x01\x01".to_vec();
+        head.extend(pre_skip.to_le_bytes());
+        head.extend(24_000u32.to_le_bytes());
+        head.extend([0, 0, 0]);
+        let tags = b"OpusTags\x04\x00\x00\x00test\x00\x00\x00\x00";
+        // 20 ms CELT frames with nothing in them, which decode as silence
+        let frame: &[u8] = &[0xf8];
+        let mut ogg = ogg_page(0x02, 0, 0, &[&head]);
+        ogg.extend(ogg_page(0x00, 0, 1, &[tags]));
+        ogg.extend(ogg_page(0x04, 3 * 960, 2, &[frame, frame, frame]));
+
+        let clip = decode(&ogg).unwrap();
+        assert_eq!((clip.channels, clip.sample_rate), (1, 48_000));
+        // the pre-skip is the decoder warming up, not part of the audio
+        assert_eq!(clip.samples.len(), 3 * 960 - pre_skip as usize);
+        assert!(clip.samples.iter().all(|s| s.abs() < 0.01));
+    }
+}
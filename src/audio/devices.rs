@@ -0,0 +1,110 @@
+//! Picking audio devices by name, and listing what is available to pick from.
+
+use cpal::traits::{DeviceTrait, HostTrait};
+
+/// The configured input device, or the host default when none is configured.
+pub fn input(host: &cpal::Host, wanted: Option<&str>) -> anyhow::Result<cpal::Device> {
+    match wanted {
+        Some(wanted) => find(host.input_devices()?, wanted, "input"),
+        None => host
+            .default_input_device()
+            .ok_or(anyhow::anyhow!("No default input device found")),
+    }
+}
+
+/// The configured output device, or the host default when none is configured.
+pub fn output(host: &cpal::Host, wanted: Option<&str>) -> anyhow::Result<cpal::Device> {
+    match wanted {
+        Some(wanted) => find(host.output_devices()?, wanted, "output"),
+        None => host
+            .default_output_device()
+            .ok_or(anyhow::anyhow!("No default output device found")),
+    }
+}
+
+/// An exact name match wins. Otherwise `wanted` must be a case-insensitive substring of
+/// exactly one device name.
+fn find(
+    devices: impl Iterator<Item = cpal::Device>,
+    wanted: &str,
+    kind: &str,
+) -> anyhow::Result<cpal::Device> {
+    let named: Vec<(String, cpal::Device)> = devices
+        .filter_map(|device| Some((device.name().ok()?, device)))
+        .collect();
+    let names: Vec<&str> = named.iter().map(|(name, _)| name.as_str()).collect();
+
+    let lowercase = wanted.to_lowercase();
+    let exact = named.iter().position(|(name, _)| name == wanted);
+    let partial: Vec<usize> = (0..named.len())
+        .filter(|&i| named[i].0.to_lowercase().contains(&lowercase))
+        .collect();
+
+    let index = match (exact, partial.as_slice()) {
+        (Some(i), _) | (None, &[i]) => i,
+        (None, []) => anyhow::bail!(
+            "No {kind} device matches {wanted:?}. Available {kind} devices: {names:?}. \
+             Run `ushidashi devices` for details."
+        ),
+        (None, _) => anyhow::bail!(
+            "{wanted:?} matches more than one {kind} device: {:?}",
+            partial.iter().map(|&i| names[i]).collect::<Vec<_>>()
+        ),
+    };
+    Ok(named.into_iter().nth(index).unwrap().1)
+}
+
+/// Print every host, its devices and the stream configs each device supports.
+pub fn list() -> anyhow::Result<()> {
+    let default_host = cpal::default_host().id();
+    for host_id in cpal::available_hosts() {
+        let host = cpal::host_from_id(host_id)?;
+        let marker = if host_id == default_host {
+            " (default)"
+        } else {
+            ""
+        };
+        println!("host: {}{marker}", host_id.name());
+
+        let default_input = host.default_input_device().and_then(|d| d.name().ok());
+        let default_output = host.default_output_device().and_then(|d| d.name().ok());
+
+        for device in host.devices()? {
+            let name = device.name().unwrap_or_else(|e| format!("<{e}>"));
+            let mut tags = Vec::new();
+            if default_input.as_ref() == Some(&name) {
+                tags.push("default input");
+            }
+            if default_output.as_ref() == Some(&name) {
+                tags.push("default output");
+            }
+            if tags.is_empty() {
+                println!("  device: {name}");
+            } else {
+                println!("  device: {name} ({})", tags.join(", "));
+            }
+
+            if let Ok(configs) = device.supported_input_configs() {
+                for config in configs {
+                    println!("    input:  {}", describe(&config));
+                }
+            }
+            if let Ok(configs) = device.supported_output_configs() {
+                for config in configs {
+                    println!("    output: {}", describe(&config));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn describe(config: &cpal::SupportedStreamConfigRange) -> String {
+    format!(
+        "{} ch, {}-{} Hz, {}",
+        config.channels(),
+        config.min_sample_rate().0,
+        config.max_sample_rate().0,
+        config.sample_format(),
+    )
+}
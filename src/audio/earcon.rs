@@ -0,0 +1,131 @@
+//! Short sounds that tell a child what the toy is doing: listening, done listening, thinking,
+//! or something went wrong.
+
+use std::f32::consts::TAU;
+
+use xdg::BaseDirectories;
+
+use super::{from_wav, Clip};
+use crate::consts::PROJECT_NAME;
+
+const SAMPLE_RATE: u32 = 24_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Earcon {
+    /// Hands-free recording has started.
+    Listening,
+    /// Recording has stopped.
+    Stopped,
+    /// The recording was too short or too quiet to be a question, and was dropped.
+    Rejected,
+    /// Looped while waiting on transcription, the chat model and speech synthesis.
+    Thinking,
+    /// Something went wrong with the last question.
+    Error,
+}
+
+impl Earcon {
+    const ALL: [Earcon; 5] = [
+        Earcon::Listening,
+        Earcon::Stopped,
+        Earcon::Rejected,
+        Earcon::Thinking,
+        Earcon::Error,
+    ];
+
+    /// Name of the WAV file in the `earcons` config directory that replaces the built-in sound.
+    fn file_name(self) -> &'static str {
+        match self {
+            Earcon::Listening => "listening.wav",
+            Earcon::Stopped => "stopped.wav",
+            Earcon::Rejected => "rejected.wav",
+            Earcon::Thinking => "thinking.wav",
+            Earcon::Error => "error.wav",
+        }
+    }
+
+    fn built_in(self) -> Clip {
+        let samples = match self {
+            Earcon::Listening => [chime(659.3, 0.09), chime(880.0, 0.16)].concat(),
+            Earcon::Stopped => [chime(880.0, 0.09), chime(659.3, 0.16)].concat(),
+            Earcon::Rejected => [chime(392.0, 0.08), chime(329.6, 0.14)].concat(),
+            Earcon::Thinking => [
+                chime(523.3, 0.12),
+                silence(0.28),
+                chime(587.3, 0.12),
+                silence(0.68),
+            ]
+            .concat()
+            .into_iter()
+            .map(|s| s * 0.4)
+            .collect(),
+            Earcon::Error => [buzz(311.1, 0.22), buzz(207.7, 0.38)].concat(),
+        };
+        Clip {
+            samples,
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+        }
+    }
+}
+
+/// The sound set, loaded once at startup.
+pub struct Earcons {
+    clips: Vec<(Earcon, Clip)>,
+}
+
+impl Earcons {
+    /// Use the built-in sounds, except where a replacement WAV exists in
+    /// `$XDG_CONFIG_HOME/ushidashi/earcons/`.
+    pub fn load() -> anyhow::Result<Self> {
+        let base = BaseDirectories::with_prefix(PROJECT_NAME)?;
+        let mut clips = Vec::new();
+        for earcon in Earcon::ALL {
+            let path = base.find_config_file(format!("earcons/{}", earcon.file_name()));
+            let clip = match path {
+                Some(path) => {
+                    eprintln!("using {path:?} for the {earcon:?} earcon");
+                    let wav = std::fs::read(&path)?;
+                    from_wav(&wav).map_err(|e| anyhow::anyhow!("Failed to read {path:?}: {e}"))?
+                }
+                None => earcon.built_in(),
+            };
+            clips.push((earcon, clip));
+        }
+        Ok(Self { clips })
+    }
+
+    pub fn get(&self, earcon: Earcon) -> &Clip {
+        let (_, clip) = self.clips.iter().find(|(e, _)| *e == earcon).unwrap();
+        clip
+    }
+}
+
+/// A bell-like tone that fades out.
+fn chime(freq: f32, seconds: f32) -> Vec<f32> {
+    tone(seconds, |t| {
+        let envelope = (t * 400.0).min(1.0) * (-t * 12.0).exp();
+        0.3 * envelope * ((TAU * freq * t).sin() + 0.3 * (2.0 * TAU * freq * t).sin())
+    })
+}
+
+/// A duller, rougher tone for bad news.
+fn buzz(freq: f32, seconds: f32) -> Vec<f32> {
+    tone(seconds, |t| {
+        let envelope = (t * 200.0).min(1.0) * ((seconds - t) * 40.0).min(1.0);
+        let wave: f32 = [1.0, 3.0, 5.0]
+            .iter()
+            .map(|h| (h * TAU * freq * t).sin() / h)
+            .sum();
+        0.2 * envelope * wave
+    })
+}
+
+fn silence(seconds: f32) -> Vec<f32> {
+    tone(seconds, |_| 0.0)
+}
+
+fn tone(seconds: f32, f: impl Fn(f32) -> f32) -> Vec<f32> {
+    let n = (seconds * SAMPLE_RATE as f32) as usize;
+    (0..n).map(|i| f(i as f32 / SAMPLE_RATE as f32)).collect()
+}
@@ -0,0 +1,146 @@
+//! Backends that need no sound hardware, for running the whole loop on a headless machine.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use tokio::sync::mpsc::unbounded_channel;
+
+use super::{from_wav, to_wav, AudioSink, AudioSource, Clip, Sound, Take};
+
+/// Plays back a WAV file as if it had been spoken into the microphone. Every recording gets
+/// the whole file at once, and then runs dry.
+pub struct WavFileSource {
+    clip: Clip,
+}
+
+impl WavFileSource {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let wav = std::fs::read(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read source file {path:?}: {e}"))?;
+        Ok(Self {
+            clip: from_wav(&wav)?,
+        })
+    }
+}
+
+impl AudioSource for WavFileSource {
+    fn channels(&self) -> u16 {
+        self.clip.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.clip.sample_rate
+    }
+
+    /// The file is all question, so there is never any pre-roll.
+    fn begin(&mut self, _preroll: bool) -> Take {
+        let (chunks, recorded) = unbounded_channel();
+        let _ = chunks.send(self.clip.samples.clone());
+        Take {
+            preroll: 0,
+            channels: self.clip.channels,
+            sample_rate: self.clip.sample_rate,
+            chunks: recorded,
+        }
+    }
+
+    fn end(&mut self) {}
+}
+
+/// Writes everything that would have been played to numbered WAV files in a directory.
+/// Looped sounds, like the thinking earcon, are left out since they have no end.
+pub struct WavFileSink {
+    dir: PathBuf,
+    written: AtomicUsize,
+}
+
+impl WavFileSink {
+    pub fn new(dir: &Path) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| anyhow::anyhow!("Failed to create sink directory {dir:?}: {e}"))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            written: AtomicUsize::new(0),
+        })
+    }
+}
+
+impl AudioSink for WavFileSink {
+    fn start(&self, clip: &Clip, looped: bool) -> anyhow::Result<Sound> {
+        if !looped {
+            let n = self.written.fetch_add(1, Ordering::Relaxed);
+            std::fs::write(self.dir.join(format!("{n:04}.wav")), to_wav(clip)?)?;
+        }
+        Ok(Sound::finished(clip))
+    }
+}
+
+/// Discards everything.
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn start(&self, clip: &Clip, _looped: bool) -> anyhow::Result<Sound> {
+        Ok(Sound::finished(clip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::to_i16;
+
+    #[tokio::test]
+    async fn plays_back_what_the_source_file_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let spoken = Clip {
+            samples: (0..1_600).map(|i| (i as f32 / 400.0).sin() * 0.5).collect(),
+            channels: 2,
+            sample_rate: 8_000,
+        };
+        let source_path = dir.path().join("question.wav");
+        std::fs::write(&source_path, to_wav(&spoken).unwrap()).unwrap();
+
+        let mut source = WavFileSource::open(&source_path).unwrap();
+        assert_eq!((source.channels(), source.sample_rate()), (2, 8_000));
+        let mut take = source.begin(true);
+        assert_eq!(
+            (take.preroll, take.channels, take.sample_rate),
+            (0, 2, 8_000)
+        );
+        let mut samples = Vec::new();
+        while let Some(chunk) = take.chunks.recv().await {
+            samples.extend(chunk);
+        }
+        source.end();
+
+        let sink = WavFileSink::new(&dir.path().join("out")).unwrap();
+        let recorded = Clip {
+            samples,
+            channels: take.channels,
+            sample_rate: take.sample_rate,
+        };
+        sink.start(&recorded, false).unwrap().wait().await.unwrap();
+        // the thinking earcon loops, and has no end to write
+        sink.start(&recorded, true).unwrap();
+        NullSink
+            .start(&recorded, false)
+            .unwrap()
+            .wait()
+            .await
+            .unwrap();
+
+        let written: Vec<_> = std::fs::read_dir(dir.path().join("out"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(written, ["0000.wav"]);
+        let wav = std::fs::read(dir.path().join("out/0000.wav")).unwrap();
+        let mut reader = hound::WavReader::new(wav.as_slice()).unwrap();
+        assert_eq!(reader.spec().channels, 2);
+        assert_eq!(reader.spec().sample_rate, 8_000);
+        let played: Vec<i16> = reader.samples().map(Result::unwrap).collect();
+        assert_eq!(played, to_i16(&spoken.samples));
+    }
+}
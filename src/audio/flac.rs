@@ -0,0 +1,133 @@
+//! FLAC encoding of 16-bit PCM, through flacenc.
+
+use flacenc::{component::BitRepr, error::Verify};
+
+/// Encode interleaved 16-bit samples as a FLAC stream. A trailing partial frame, as a
+/// truncated WAV file can end with, is dropped.
+pub fn encode(samples: &[i16], channels: u16, sample_rate: u32) -> anyhow::Result<Vec<u8>> {
+    anyhow::ensure!(
+        (1..=8).contains(&channels),
+        "FLAC supports 1 to 8 channels, not {channels}"
+    );
+    anyhow::ensure!(
+        (1..1 << 20).contains(&sample_rate),
+        "FLAC supports sample rates from 1 Hz up to 2^20 Hz, not {sample_rate} Hz"
+    );
+    let channels = channels as usize;
+    let whole_frames = samples.len() / channels * channels;
+    let samples: Vec<i32> = samples[..whole_frames].iter().map(|&s| s as i32).collect();
+
+    let config = flacenc::config::Encoder::default()
+        .into_verified()
+        .map_err(|(_, e)| anyhow::anyhow!("Bad FLAC encoder config: {e}"))?;
+    let source =
+        flacenc::source::MemSource::from_samples(&samples, channels, 16, sample_rate as usize);
+    let mut stream = flacenc::encode_with_fixed_block_size(&config, source, config.block_size)
+        .map_err(|e| anyhow::anyhow!("Failed to encode FLAC: {e}"))?;
+    // flacenc counts the short last block in the smallest block size, which the format
+    // leaves out and some decoders, symphonia among them, refuse
+    let frames = whole_frames / channels;
+    stream
+        .stream_info_mut()
+        .set_block_sizes(config.block_size.min(frames.max(16)), config.block_size)
+        .map_err(|e| anyhow::anyhow!("Bad FLAC block sizes: {e}"))?;
+    let mut sink = flacenc::bitsink::ByteSink::new();
+    stream
+        .write(&mut sink)
+        .map_err(|e| anyhow::anyhow!("Failed to write FLAC: {e}"))?;
+    Ok(sink.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use symphonia::core::{
+        audio::SampleBuffer, codecs::DecoderOptions, formats::FormatOptions, io::MediaSourceStream,
+        meta::MetadataOptions, probe::Hint,
+    };
+
+    fn decode(flac: Vec<u8>) -> (Vec<i16>, u32, usize) {
+        let source =
+            MediaSourceStream::new(Box::new(std::io::Cursor::new(flac)), Default::default());
+        let mut format = symphonia::default::get_probe()
+            .format(
+                &Hint::new(),
+                source,
+                &FormatOptions::default(),
+                &MetadataOptions::default(),
+            )
+            .unwrap()
+            .format;
+        let track = format.default_track().unwrap();
+        let params = track.codec_params.clone();
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&params, &DecoderOptions { verify: true })
+            .unwrap();
+        let mut samples = Vec::new();
+        while let Ok(packet) = format.next_packet() {
+            let decoded = decoder.decode(&packet).unwrap();
+            let mut buffer = SampleBuffer::<i16>::new(decoded.capacity() as u64, *decoded.spec());
+            buffer.copy_interleaved_ref(decoded);
+            samples.extend_from_slice(buffer.samples());
+        }
+        (
+            samples,
+            params.sample_rate.unwrap(),
+            params.channels.unwrap().count(),
+        )
+    }
+
+    #[test]
+    fn round_trips_speechlike_stereo() {
+        // a chirp with some noise, plus a silent stretch and full scale samples
+        let mut seed = 1u32;
+        let mut samples: Vec<i16> = (0..20_000)
+            .flat_map(|i| {
+                seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                let t = i as f32 / 16_000.0;
+                let s = 8000.0 * (t * (300.0 + 2000.0 * t) * std::f32::consts::TAU).sin();
+                let noise = (seed >> 22) as f32 - 512.0;
+                [(s + noise) as i16, (s * 0.5) as i16]
+            })
+            .collect();
+        samples.extend(std::iter::repeat_n(0, 6000));
+        samples.extend([i16::MAX, i16::MIN, i16::MIN, i16::MAX]);
+
+        let flac = encode(&samples, 2, 16_000).unwrap();
+        assert!(flac.len() < samples.len() * 2);
+        assert_eq!(decode(flac), (samples, 16_000, 2));
+    }
+
+    #[test]
+    fn round_trips_white_noise() {
+        let mut seed = 7u32;
+        let samples: Vec<i16> = (0..10_000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (seed >> 16) as i16
+            })
+            .collect();
+        assert_eq!(
+            decode(encode(&samples, 1, 8_000).unwrap()),
+            (samples, 8_000, 1)
+        );
+    }
+
+    #[test]
+    fn drops_a_trailing_partial_frame() {
+        let samples: Vec<i16> = (0..10_001).map(|i| (i % 1000) as i16).collect();
+        assert_eq!(
+            decode(encode(&samples, 2, 16_000).unwrap()),
+            (samples[..10_000].to_vec(), 16_000, 2)
+        );
+    }
+
+    #[test]
+    fn rejects_what_the_format_cannot_hold() {
+        assert!(encode(&[0; 9], 9, 16_000).is_err());
+        assert!(encode(&[], 0, 16_000).is_err());
+        assert!(encode(&[0; 2], 1, 1 << 20).is_err());
+        assert!(encode(&[], 1, 16_000).is_ok());
+    }
+}
@@ -0,0 +1,190 @@
+//! Playback level: loudness normalization, master volume and a peak limiter.
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use super::Clip;
+use crate::chatlog;
+
+/// Blocks quieter than this are left out when measuring loudness, so pauses between sentences
+/// don't make speech seem quieter than it is.
+const GATE_DB: f32 = -50.0;
+
+/// Playback level settings. They live in the data directory rather than config.toml because
+/// `ushidashi volume` changes them, and they are re-read before every sound so a change takes
+/// effect on a toy that is already running.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Level {
+    /// Master volume from 0.0 to 1.0, applied after normalization.
+    pub volume: f32,
+    /// Bring speech toward `target_db` before applying the volume.
+    pub normalize: bool,
+    /// Loudness target for normalization, in dBFS RMS.
+    pub target_db: f32,
+    /// The most normalization may boost a quiet clip by, in dB.
+    pub max_gain_db: f32,
+    /// Peaks are limited to this level in dBFS, so the speaker never clips.
+    pub ceiling_db: f32,
+}
+
+impl Default for Level {
+    fn default() -> Self {
+        Self {
+            volume: 0.8,
+            normalize: true,
+            target_db: -20.0,
+            max_gain_db: 20.0,
+            ceiling_db: -1.0,
+        }
+    }
+}
+
+impl Level {
+    fn path() -> anyhow::Result<PathBuf> {
+        Ok(chatlog::data_dir()?.join("level.toml"))
+    }
+
+    /// The stored settings, or the defaults if none were ever stored. A damaged file is
+    /// reported and ignored rather than silencing the toy.
+    pub fn load() -> anyhow::Result<Self> {
+        let path = Self::path()?;
+        let level = match std::fs::read_to_string(&path) {
+            Ok(level) => level,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(toml::from_str(&level).unwrap_or_else(|e| {
+            eprintln!("ignoring {path:?}: {e}");
+            Self::default()
+        }))
+    }
+
+    pub fn store(&self) -> anyhow::Result<()> {
+        std::fs::write(Self::path()?, toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Level a clip for playback. Normalization is meant for speech; short designed sounds
+    /// like earcons should only get the volume and limiter.
+    pub fn apply(&self, clip: &mut Clip, normalize: bool) {
+        let mut gain = self.volume.clamp(0.0, 1.0);
+        if normalize && self.normalize {
+            if let Some(loudness) = loudness_db(clip) {
+                let boost = (self.target_db - loudness).min(self.max_gain_db);
+                gain *= db_to_amplitude(boost);
+            }
+        }
+        clip.samples.iter_mut().for_each(|s| *s *= gain);
+        limit(clip, self.ceiling());
+    }
+
+    /// The ceiling as a sample value.
+    pub fn ceiling(&self) -> f32 {
+        db_to_amplitude(self.ceiling_db.min(0.0))
+    }
+}
+
+fn db_to_amplitude(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// Gated RMS loudness over 50 ms blocks, or None for a silent clip.
+fn loudness_db(clip: &Clip) -> Option<f32> {
+    let block = (clip.sample_rate as usize / 20 * clip.channels as usize).max(1);
+    let powers: Vec<f32> = clip
+        .samples
+        .chunks(block)
+        .map(|b| b.iter().map(|s| s * s).sum::<f32>() / b.len() as f32)
+        .filter(|&p| 10.0 * p.max(f32::MIN_POSITIVE).log10() > GATE_DB)
+        .collect();
+    if powers.is_empty() {
+        return None;
+    }
+    let mean = powers.iter().sum::<f32>() / powers.len() as f32;
+    Some(10.0 * mean.log10())
+}
+
+/// Keep every sample within `ceiling`. The whole clip is known up front, so the limiter looks
+/// ahead: gain ramps down over a few milliseconds before a peak and recovers after it, rather
+/// than flattening the peak itself.
+fn limit(clip: &mut Clip, ceiling: f32) {
+    let channels = clip.channels.max(1) as usize;
+    let rate = clip.sample_rate as f32;
+    let attack = (-1.0 / (0.005 * rate)).exp();
+    let release = (-1.0 / (0.080 * rate)).exp();
+
+    // the gain each frame needs on its own
+    let mut gains: Vec<f32> = clip
+        .samples
+        .chunks(channels)
+        .map(|frame| {
+            let peak = frame.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+            if peak > ceiling {
+                ceiling / peak
+            } else {
+                1.0
+            }
+        })
+        .collect();
+    if gains.iter().all(|&g| g == 1.0) {
+        return;
+    }
+
+    // never rise faster than `attack` allows going backwards, or `release` going forwards;
+    // taking the minimum keeps every gain at or below what its frame needs
+    for i in (0..gains.len().saturating_sub(1)).rev() {
+        gains[i] = gains[i].min(1.0 - (1.0 - gains[i + 1]) * attack);
+    }
+    for i in 1..gains.len() {
+        gains[i] = gains[i].min(1.0 - (1.0 - gains[i - 1]) * release);
+    }
+
+    for (frame, gain) in clip.samples.chunks_mut(channels).zip(gains) {
+        // the clamp only catches rounding error
+        frame
+            .iter_mut()
+            .for_each(|s| *s = (*s * gain).clamp(-ceiling, ceiling));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(amplitude: f32) -> Clip {
+        Clip {
+            samples: (0..24_000)
+                .map(|i| amplitude * (i as f32 * 0.1).sin())
+                .collect(),
+            channels: 1,
+            sample_rate: 24_000,
+        }
+    }
+
+    #[test]
+    fn limiter_holds_the_ceiling() {
+        let level = Level {
+            volume: 1.0,
+            normalize: false,
+            ..Level::default()
+        };
+        let mut clip = sine(3.0);
+        level.apply(&mut clip, true);
+        let ceiling = db_to_amplitude(level.ceiling_db);
+        assert!(clip.samples.iter().all(|s| s.abs() <= ceiling));
+    }
+
+    #[test]
+    fn normalization_reaches_target() {
+        let level = Level {
+            volume: 1.0,
+            ..Level::default()
+        };
+        let mut clip = sine(0.02);
+        level.apply(&mut clip, true);
+        let loudness = loudness_db(&clip).unwrap();
+        assert!((loudness - level.target_db).abs() < 0.5, "{loudness}");
+    }
+}
@@ -0,0 +1,769 @@
+//! The sound card, through cpal. A single engine thread owns the input and output streams for
+//! the life of the program, and everything else talks to it over a channel, to play sounds
+//! and to start and stop recordings. Keeping the streams open saves setting up a device for
+//! every sound, which is slow and makes some ALSA devices pop. When a stream fails, say
+//! because a USB sound card was unplugged, the engine drops it and keeps trying to open the
+//! device again until it is back. The device that comes back may not be the same one, so its
+//! format is worked out afresh each time.
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{FromSample, SampleFormat, SizedSample};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use super::{devices, AudioSink, AudioSource, Clip, Level, Sound, Take};
+use crate::config;
+
+/// How often to try reopening a device that failed.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Input,
+    Output,
+}
+
+impl Direction {
+    fn name(self) -> &'static str {
+        match self {
+            Direction::Input => "input",
+            Direction::Output => "output",
+        }
+    }
+}
+
+enum Message {
+    Play(Voice),
+    /// Start a recording, see [`Capture::begin`]. The reply is how many samples of pre-roll
+    /// were sent and the format the audio is in.
+    Begin {
+        preroll: bool,
+        chunks: UnboundedSender<Vec<f32>>,
+        reply: mpsc::Sender<(usize, cpal::StreamConfig)>,
+    },
+    End,
+    /// Send audio heard between recordings here, instead of to whoever listened before.
+    Listen(UnboundedSender<Vec<f32>>),
+    /// A stream reported an error. `generation` tells errors from a stream that has since been
+    /// replaced apart from errors from the current one.
+    Failed {
+        direction: Direction,
+        generation: usize,
+        error: cpal::StreamError,
+    },
+    Shutdown,
+}
+
+/// Handle to the engine thread. Only the directions the config routes through the sound card
+/// are opened, so a headless setup with file sources and sinks never touches a device.
+pub struct Engine {
+    messages: mpsc::Sender<Message>,
+    /// the format of each direction's device as last opened, if the direction is used
+    input: Option<Format>,
+    output: Option<Format>,
+}
+
+/// The stream config a device was last opened with, shared with the engine thread which
+/// changes it when the device is reopened in another format.
+type Format = Arc<Mutex<Option<cpal::StreamConfig>>>;
+
+impl Engine {
+    /// Start the engine and open the configured devices. Failing to open a device at all is
+    /// an error here, so a missing device is noticed before anyone asks a question.
+    pub fn start(
+        audio: &config::Audio,
+        recording: &config::Recording,
+        tts: &config::Tts,
+    ) -> anyhow::Result<Arc<Self>> {
+        let capture = Arc::new(Mutex::new(Capture {
+            preroll: VecDeque::new(),
+            preroll_duration: recording.preroll(),
+            preroll_len: 0,
+            recording: None,
+            idle: None,
+        }));
+        let input_format = (audio.source == config::Source::Device).then(Format::default);
+        let output_format = (audio.sink == config::Sink::Device).then(Format::default);
+        let input = input_format
+            .clone()
+            .map(|format| (audio.input_device.clone(), format));
+        let output = output_format
+            .clone()
+            .map(|format| (audio.output_device.clone(), format));
+        let speech_rate = tts.encoding.sample_rate();
+
+        let (messages, inbox) = mpsc::channel();
+        let (ready_tx, ready) = mpsc::channel();
+        let errors = messages.clone();
+        thread::spawn(move || {
+            // streams can't move between threads, so they are opened on this one
+            let mut streams = Streams {
+                input: input.map(|(name, format)| Side::new(name, None, format)),
+                output: output.map(|(name, format)| Side::new(name, Some(speech_rate), format)),
+                capture,
+                mixer: Arc::new(Mutex::new(Mixer { voices: Vec::new() })),
+            };
+            let opened = streams.open_all(&errors);
+            let ok = opened.is_ok();
+            let _ = ready_tx.send(opened);
+            if ok {
+                streams.run(&inbox, &errors);
+            }
+        });
+        ready.recv()??;
+
+        Ok(Arc::new(Self {
+            messages,
+            input: input_format,
+            output: output_format,
+        }))
+    }
+
+    fn send(&self, message: Message) {
+        // the engine thread only stops once this handle is dropped
+        let _ = self.messages.send(message);
+    }
+}
+
+impl Drop for Engine {
+    fn drop(&mut self) {
+        self.send(Message::Shutdown);
+    }
+}
+
+/// One direction of the sound card, as seen from the engine thread.
+struct Side {
+    device_name: Option<String>,
+    /// the sample rate to open the device at when it supports it, rather than its default
+    preferred_rate: Option<u32>,
+    /// negotiated every time the device is opened
+    format: Format,
+    stream: Option<cpal::Stream>,
+    generation: usize,
+    /// whether the current outage has been reported
+    reported: bool,
+}
+
+impl Side {
+    fn new(device_name: Option<String>, preferred_rate: Option<u32>, format: Format) -> Self {
+        Self {
+            device_name,
+            preferred_rate,
+            format,
+            stream: None,
+            generation: 0,
+            reported: false,
+        }
+    }
+
+    /// Take `config` as the device's format from now on. Returns whether it changed from
+    /// the last time the device was opened.
+    fn renegotiate(&mut self, config: &cpal::StreamConfig, direction: Direction) -> bool {
+        let mut format = self.format.lock().unwrap();
+        let changed = format.as_ref() != Some(config);
+        if changed && format.is_some() {
+            eprintln!(
+                "The audio {} device now has {} channels at {} Hz",
+                direction.name(),
+                config.channels,
+                config.sample_rate.0
+            );
+        }
+        *format = Some(config.clone());
+        changed
+    }
+}
+
+struct Streams {
+    input: Option<Side>,
+    output: Option<Side>,
+    capture: Arc<Mutex<Capture>>,
+    mixer: Arc<Mutex<Mixer>>,
+}
+
+impl Streams {
+    fn run(&mut self, inbox: &mpsc::Receiver<Message>, errors: &mpsc::Sender<Message>) {
+        loop {
+            let message = match inbox.recv_timeout(RETRY_INTERVAL) {
+                Ok(message) => message,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    let _ = self.open_all(errors);
+                    continue;
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            };
+            match message {
+                Message::Play(voice) => match &self.output {
+                    Some(side) if side.stream.is_some() => {
+                        if side.format.lock().unwrap().as_ref() == Some(&voice.config) {
+                            self.mixer.lock().unwrap().voices.push(voice);
+                        } else {
+                            voice.fail("the output device changed format");
+                        }
+                    }
+                    _ => voice.fail("the output device is unavailable"),
+                },
+                Message::Begin {
+                    preroll,
+                    chunks,
+                    reply,
+                } => {
+                    let Some(side) = &self.input else { continue };
+                    let sent = self.capture.lock().unwrap().begin(preroll, chunks);
+                    let config = side.format.lock().unwrap().clone();
+                    // the input was opened at start, so it has a format even while it's away
+                    let _ = reply.send((sent, config.unwrap()));
+                }
+                Message::End => self.capture.lock().unwrap().recording = None,
+                Message::Listen(idle) => self.capture.lock().unwrap().idle = Some(idle),
+                Message::Failed {
+                    direction,
+                    generation,
+                    error,
+                } => {
+                    let side = match direction {
+                        Direction::Input => &mut self.input,
+                        Direction::Output => &mut self.output,
+                    };
+                    let Some(side) = side.as_mut() else { continue };
+                    if side.generation != generation || side.stream.is_none() {
+                        continue;
+                    }
+                    eprintln!(
+                        "The audio {} stream failed, reopening it: {error}",
+                        direction.name()
+                    );
+                    side.stream = None;
+                    if direction == Direction::Output {
+                        for voice in self.mixer.lock().unwrap().voices.drain(..) {
+                            voice.fail(&error.to_string());
+                        }
+                    }
+                    let _ = self.open_all(errors);
+                }
+                Message::Shutdown => return,
+            }
+        }
+    }
+
+    /// Open whichever wanted streams aren't running.
+    fn open_all(&mut self, errors: &mpsc::Sender<Message>) -> anyhow::Result<()> {
+        let mut result = Ok(());
+        for direction in [Direction::Input, Direction::Output] {
+            let side = match direction {
+                Direction::Input => &mut self.input,
+                Direction::Output => &mut self.output,
+            };
+            let Some(side) = side.as_mut() else { continue };
+            if side.stream.is_some() {
+                continue;
+            }
+            side.generation += 1;
+            let opened = match direction {
+                Direction::Input => open_input(side, &self.capture, errors),
+                Direction::Output => open_output(side, &self.mixer, errors),
+            };
+            match opened {
+                Ok(stream) => {
+                    if side.reported {
+                        eprintln!("The audio {} device is back", direction.name());
+                    }
+                    side.stream = Some(stream);
+                    side.reported = false;
+                }
+                Err(e) => {
+                    if !side.reported {
+                        eprintln!(
+                            "The audio {} device is unavailable: {e:#}",
+                            direction.name()
+                        );
+                        side.reported = true;
+                    }
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Report stream errors to the engine thread, tagged with the stream they came from.
+fn error_callback(
+    errors: &mpsc::Sender<Message>,
+    direction: Direction,
+    generation: usize,
+) -> impl FnMut(cpal::StreamError) + Send + 'static {
+    let errors = errors.clone();
+    move |error| {
+        let _ = errors.send(Message::Failed {
+            direction,
+            generation,
+            error,
+        });
+    }
+}
+
+fn open_input(
+    side: &mut Side,
+    capture: &Arc<Mutex<Capture>>,
+    errors: &mpsc::Sender<Message>,
+) -> anyhow::Result<cpal::Stream> {
+    let host = cpal::default_host();
+    let device = devices::input(&host, side.device_name.as_deref())?;
+    let supported = device
+        .default_input_config()
+        .map_err(|e| anyhow::anyhow!("Failed to query the input device config: {}", e))?;
+    let sample_format = supported.sample_format();
+    let config: cpal::StreamConfig = supported.into();
+    if side.renegotiate(&config, Direction::Input) {
+        // audio in the old format can't be mixed with the new
+        capture.lock().unwrap().reformat(&config);
+    }
+
+    let on_error = error_callback(errors, Direction::Input, side.generation);
+    let stream = match sample_format {
+        SampleFormat::I16 => build_input_stream::<i16>(&device, &config, capture, on_error),
+        SampleFormat::U16 => build_input_stream::<u16>(&device, &config, capture, on_error),
+        SampleFormat::I32 => build_input_stream::<i32>(&device, &config, capture, on_error),
+        SampleFormat::F32 => build_input_stream::<f32>(&device, &config, capture, on_error),
+        other => anyhow::bail!("Unsupported input sample format: {}", other),
+    }
+    .map_err(|e| anyhow::anyhow!("Failed to build audio input stream: {}", e))?;
+    stream
+        .play()
+        .map_err(|e| anyhow::anyhow!("Failed to start audio input stream: {}", e))?;
+    Ok(stream)
+}
+
+fn open_output(
+    side: &mut Side,
+    mixer: &Arc<Mutex<Mixer>>,
+    errors: &mpsc::Sender<Message>,
+) -> anyhow::Result<cpal::Stream> {
+    let host = cpal::default_host();
+    let device = devices::output(&host, side.device_name.as_deref())?;
+    let supported = device
+        .default_output_config()
+        .map_err(|e| anyhow::anyhow!("Failed to query the output device config: {}", e))?;
+    let supported = match side.preferred_rate {
+        Some(rate) => at_rate(&device, supported, cpal::SampleRate(rate)),
+        None => supported,
+    };
+    let sample_format = supported.sample_format();
+    let config: cpal::StreamConfig = supported.into();
+    side.renegotiate(&config, Direction::Output);
+
+    let on_error = error_callback(errors, Direction::Output, side.generation);
+    let stream = match sample_format {
+        SampleFormat::I16 => build_output_stream::<i16>(&device, &config, mixer, on_error),
+        SampleFormat::U16 => build_output_stream::<u16>(&device, &config, mixer, on_error),
+        SampleFormat::I32 => build_output_stream::<i32>(&device, &config, mixer, on_error),
+        SampleFormat::F32 => build_output_stream::<f32>(&device, &config, mixer, on_error),
+        other => anyhow::bail!("Unsupported output sample format: {}", other),
+    }
+    .map_err(|e| anyhow::anyhow!("Failed to build audio output stream: {}", e))?;
+    stream
+        .play()
+        .map_err(|e| anyhow::anyhow!("Failed to play audio output stream: {}", e))?;
+    Ok(stream)
+}
+
+/// The device's default output config, changed to play at `rate` if the device can do that
+/// with the same channels and sample format.
+fn at_rate(
+    device: &cpal::Device,
+    default: cpal::SupportedStreamConfig,
+    rate: cpal::SampleRate,
+) -> cpal::SupportedStreamConfig {
+    let Ok(mut configs) = device.supported_output_configs() else {
+        return default;
+    };
+    let found = configs.find(|range| {
+        range.channels() == default.channels()
+            && range.sample_format() == default.sample_format()
+            && (range.min_sample_rate()..=range.max_sample_rate()).contains(&rate)
+    });
+    match found {
+        Some(range) => range.with_sample_rate(rate),
+        None => default,
+    }
+}
+
+/// Where microphone input goes. While no recording is in progress the most recent audio is
+/// kept in a ring buffer, so speech that starts before the press is noticed still makes it
+/// into the recording. When listening for the wake word, that audio is also passed on to the
+/// spotter as it arrives.
+struct Capture {
+    /// interleaved samples heard just before now, at most `preroll_len` of them
+    preroll: VecDeque<f32>,
+    preroll_duration: Duration,
+    preroll_len: usize,
+    /// where audio goes during a recording
+    recording: Option<UnboundedSender<Vec<f32>>>,
+    /// where audio heard outside recordings goes, when something is listening
+    idle: Option<UnboundedSender<Vec<f32>>>,
+}
+
+impl Capture {
+    /// The input is now in this format. Recording and listening in progress are ended, since
+    /// whoever gets the audio expects it in the old one.
+    fn reformat(&mut self, config: &cpal::StreamConfig) {
+        let samples_per_second = config.sample_rate.0 as f64 * config.channels as f64;
+        self.preroll_len = (self.preroll_duration.as_secs_f64() * samples_per_second) as usize;
+        self.preroll.clear();
+        self.recording = None;
+        self.idle = None;
+    }
+
+    /// Send the recording's audio to `chunks` from now on, starting with the pre-roll if
+    /// `preroll` is set. Returns how many samples of pre-roll were sent, which is less than
+    /// asked for when the input hasn't been open that long.
+    fn begin(&mut self, preroll: bool, chunks: UnboundedSender<Vec<f32>>) -> usize {
+        let kept: Vec<f32> = self.preroll.drain(..).collect();
+        let sent = if preroll { kept.len() } else { 0 };
+        if preroll {
+            let _ = chunks.send(kept);
+        }
+        self.recording = Some(chunks);
+        sent
+    }
+
+    fn push(&mut self, data: &[f32]) {
+        match self.recording {
+            Some(ref chunks) => {
+                let _ = chunks.send(data.to_vec());
+            }
+            None => {
+                self.preroll.extend(data);
+                let excess = self.preroll.len().saturating_sub(self.preroll_len);
+                self.preroll.drain(..excess);
+                let stopped = self
+                    .idle
+                    .as_ref()
+                    .is_some_and(|idle| idle.send(data.to_vec()).is_err());
+                if stopped {
+                    self.idle = None;
+                }
+            }
+        }
+    }
+}
+
+/// Open an input stream delivering samples of type `T`, converting them to f32 as they arrive.
+fn build_input_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    capture: &Arc<Mutex<Capture>>,
+    on_error: impl FnMut(cpal::StreamError) + Send + 'static,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let capture = capture.clone();
+    let mut converted = Vec::new();
+    device.build_input_stream(
+        config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| {
+            converted.clear();
+            converted.extend(data.iter().map(|sample| sample.to_sample::<f32>()));
+            capture.lock().unwrap().push(&converted);
+        },
+        on_error,
+        None,
+    )
+}
+
+/// A clip being played, already converted to the output stream's format.
+struct Voice {
+    /// the format `samples` were converted to
+    config: cpal::StreamConfig,
+    samples: Vec<f32>,
+    position: usize,
+    looped: bool,
+    /// the limiter ceiling the clip was leveled for, which the mix has to keep to as well
+    ceiling: f32,
+    /// samples handed to the device so far
+    played: Arc<AtomicUsize>,
+    /// set when the [`Sound`] is dropped
+    stopped: Arc<AtomicBool>,
+    done: tokio::sync::mpsc::UnboundedSender<anyhow::Result<()>>,
+}
+
+impl Voice {
+    fn fail(self, reason: &str) {
+        let _ = self.done.send(Err(anyhow::anyhow!("{reason}")));
+    }
+
+    fn finished(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+            || self.samples.is_empty()
+            || (!self.looped && self.position == self.samples.len())
+    }
+}
+
+/// Sums the voices that are playing. Each was limited on its own, so voices that overlap,
+/// like an earcon over speech, are clamped to the lowest of their ceilings. With nothing to
+/// play the output is silence, but the stream keeps running.
+struct Mixer {
+    voices: Vec<Voice>,
+}
+
+impl Mixer {
+    fn mix(&mut self, out: &mut [f32]) {
+        out.fill(0.0);
+        let ceiling = self
+            .voices
+            .iter()
+            .map(|voice| voice.ceiling)
+            .fold(1.0, f32::min);
+        for voice in &mut self.voices {
+            if voice.finished() {
+                continue;
+            }
+            for sample in out.iter_mut() {
+                if voice.looped && voice.position == voice.samples.len() {
+                    voice.position = 0;
+                }
+                let Some(s) = voice.samples.get(voice.position) else {
+                    break;
+                };
+                *sample += s;
+                voice.position += 1;
+            }
+            if !voice.looped {
+                voice.played.store(voice.position, Ordering::Relaxed);
+                if voice.position == voice.samples.len() {
+                    let _ = voice.done.send(Ok(()));
+                }
+            }
+        }
+        self.voices.retain(|voice| !voice.finished());
+        for sample in out.iter_mut() {
+            *sample = sample.clamp(-ceiling, ceiling);
+        }
+    }
+}
+
+/// Open an output stream taking samples of type `T`, fed by the mixer.
+fn build_output_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mixer: &Arc<Mutex<Mixer>>,
+    on_error: impl FnMut(cpal::StreamError) + Send + 'static,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let mixer = mixer.clone();
+    let mut mixed = Vec::new();
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            mixed.resize(data.len(), 0.0);
+            mixer.lock().unwrap().mix(&mut mixed);
+            for (out, sample) in data.iter_mut().zip(&mixed) {
+                *out = T::from_sample(*sample);
+            }
+        },
+        on_error,
+        None,
+    )
+}
+
+/// Microphone input, recorded through the engine.
+pub struct DeviceSource {
+    engine: Arc<Engine>,
+    format: Format,
+}
+
+impl DeviceSource {
+    pub fn new(engine: &Arc<Engine>) -> anyhow::Result<Self> {
+        let format = engine
+            .input
+            .clone()
+            .ok_or(anyhow::anyhow!("The audio engine has no input device open"))?;
+        Ok(Self {
+            engine: engine.clone(),
+            format,
+        })
+    }
+
+    fn config(&self) -> cpal::StreamConfig {
+        // opened when the engine started
+        self.format.lock().unwrap().clone().unwrap()
+    }
+}
+
+impl AudioSource for DeviceSource {
+    fn channels(&self) -> u16 {
+        self.config().channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.config().sample_rate.0
+    }
+
+    /// Waits for the engine to hand over the pre-roll, which it does between trying to
+    /// reopen devices.
+    fn begin(&mut self, preroll: bool) -> Take {
+        let (chunks, recorded) = unbounded_channel();
+        let (reply, replied) = mpsc::channel();
+        self.engine.send(Message::Begin {
+            preroll,
+            chunks,
+            reply,
+        });
+        // an engine that stopped leaves the recording closed, and so empty
+        let (preroll, config) = replied.recv().unwrap_or_else(|_| (0, self.config()));
+        Take {
+            preroll,
+            channels: config.channels,
+            sample_rate: config.sample_rate.0,
+            chunks: recorded,
+        }
+    }
+
+    fn end(&mut self) {
+        self.engine.send(Message::End);
+    }
+
+    fn listen(&mut self) -> Option<UnboundedReceiver<Vec<f32>>> {
+        let (idle, heard) = unbounded_channel();
+        self.engine.send(Message::Listen(idle));
+        Some(heard)
+    }
+}
+
+/// Speaker output, played through the engine. Clips are converted to the format of the
+/// output stream before they are handed over, on a blocking thread since a long answer takes
+/// a while to resample.
+pub struct DeviceSink {
+    engine: Arc<Engine>,
+    format: Format,
+}
+
+impl DeviceSink {
+    pub fn new(engine: &Arc<Engine>) -> anyhow::Result<Self> {
+        let format = engine.output.clone().ok_or(anyhow::anyhow!(
+            "The audio engine has no output device open"
+        ))?;
+        Ok(Self {
+            engine: engine.clone(),
+            format,
+        })
+    }
+}
+
+impl AudioSink for DeviceSink {
+    fn start(&self, clip: &Clip, looped: bool) -> anyhow::Result<Sound> {
+        let ceiling = Level::load()?.ceiling();
+        // opened when the engine started
+        let config = self.format.lock().unwrap().clone().unwrap();
+        let (channels, sample_rate) = (config.channels, config.sample_rate.0);
+        let (done, rx) = tokio::sync::mpsc::unbounded_channel();
+        let sound = Sound::new(channels, sample_rate, rx);
+        let voice = Voice {
+            config,
+            samples: Vec::new(),
+            position: 0,
+            looped,
+            ceiling,
+            played: sound.played.clone(),
+            stopped: sound.stopped.clone(),
+            done,
+        };
+        let clip = clip.clone();
+        let engine = self.engine.clone();
+        tokio::task::spawn_blocking(move || {
+            let clip = clip.convert(channels, sample_rate);
+            engine.send(Message::Play(Voice {
+                samples: clip.samples,
+                ..voice
+            }));
+        });
+        Ok(sound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn voice(
+        samples: Vec<f32>,
+        looped: bool,
+    ) -> (
+        Voice,
+        tokio::sync::mpsc::UnboundedReceiver<anyhow::Result<()>>,
+    ) {
+        let (done, rx) = tokio::sync::mpsc::unbounded_channel();
+        let voice = Voice {
+            config: cpal::StreamConfig {
+                channels: 1,
+                sample_rate: cpal::SampleRate(48_000),
+                buffer_size: cpal::BufferSize::Default,
+            },
+            samples,
+            position: 0,
+            looped,
+            ceiling: 1.0,
+            played: Arc::new(AtomicUsize::new(0)),
+            stopped: Arc::new(AtomicBool::new(false)),
+            done,
+        };
+        (voice, rx)
+    }
+
+    #[test]
+    fn mixes_voices_until_they_end_or_stop() {
+        let (once, mut once_done) = voice(vec![0.5, 0.5, 0.5], false);
+        let (looped, _) = voice(vec![0.25, -0.25], true);
+        let stop = looped.stopped.clone();
+        let mut mixer = Mixer {
+            voices: vec![once, looped],
+        };
+
+        let mut out = [1.0; 4];
+        mixer.mix(&mut out);
+        assert_eq!(out, [0.75, 0.25, 0.75, -0.25]);
+        assert!(once_done.try_recv().unwrap().is_ok());
+        assert_eq!(mixer.voices.len(), 1);
+
+        mixer.mix(&mut out);
+        assert_eq!(out, [0.25, -0.25, 0.25, -0.25]);
+
+        stop.store(true, Ordering::Relaxed);
+        mixer.mix(&mut out);
+        assert_eq!(out, [0.0; 4]);
+        assert!(mixer.voices.is_empty());
+    }
+
+    #[test]
+    fn overlapping_voices_stay_under_the_ceiling() {
+        let (speech, _) = voice(vec![0.75, -0.75, 0.5], false);
+        let (earcon, _) = voice(vec![0.75, -0.75, 0.25], false);
+        let mut mixer = Mixer {
+            voices: vec![
+                Voice {
+                    ceiling: 0.9,
+                    ..speech
+                },
+                earcon,
+            ],
+        };
+
+        let mut out = [0.0; 3];
+        mixer.mix(&mut out);
+        assert_eq!(out, [0.9, -0.9, 0.75]);
+    }
+}
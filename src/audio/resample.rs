@@ -0,0 +1,197 @@
+//! Channel and sample rate conversion, so uploads are only as large as the transcriber needs.
+
+use std::f64::consts::PI;
+
+/// Zero crossings of the interpolation kernel on each side of the output sample. More is
+/// sharper filtering and slower conversion.
+const KERNEL_HALF_WIDTH: f64 = 16.0;
+
+/// Change the number of interleaved channels. Going to mono averages every channel, other
+/// conversions copy input channels round-robin.
+pub fn remix(samples: &[f32], from: u16, to: u16) -> Vec<f32> {
+    let (from, to) = (from.max(1) as usize, to.max(1) as usize);
+    if from == to {
+        return samples.to_vec();
+    }
+    samples
+        .chunks_exact(from)
+        .flat_map(|frame| {
+            (0..to).map(move |c| {
+                if to == 1 {
+                    frame.iter().sum::<f32>() / from as f32
+                } else {
+                    frame[c % from]
+                }
+            })
+        })
+        .collect()
+}
+
+/// Band-limited resampling of interleaved audio with a Hann-windowed sinc kernel. When
+/// downsampling, the kernel also acts as the anti-aliasing filter.
+pub fn resample(samples: &[f32], channels: u16, from: u32, to: u32) -> Vec<f32> {
+    if from == to {
+        return samples.to_vec();
+    }
+    let channels = channels.max(1) as usize;
+    let frames_in = samples.len() / channels;
+    if frames_in == 0 {
+        return Vec::new();
+    }
+    // output frame n falls at input frame n * down / up, which repeats the same fractional
+    // positions every `up` frames
+    let divisor = gcd(from, to);
+    let (up, down) = ((to / divisor) as u64, (from / divisor) as u64);
+    let ratio = up as f64 / down as f64;
+    let frames_out = (frames_in as f64 * ratio).round() as u64;
+
+    // cutoff relative to the input Nyquist frequency
+    let cutoff = ratio.min(1.0);
+    let half_width = KERNEL_HALF_WIDTH / cutoff;
+    let reach = half_width.ceil() as i64;
+    let taps = 2 * reach as usize + 1;
+    let weights = |phase: u64, into: &mut Vec<f64>| {
+        let fraction = phase as f64 / up as f64;
+        into.extend((-reach..=reach).map(|j| kernel(fraction - j as f64, cutoff, half_width)));
+    };
+    // the weights for every fractional position, unless there are too many to be worth it
+    let table = (up <= MAX_PHASES).then(|| {
+        let mut table = Vec::with_capacity(up as usize * taps);
+        (0..up).for_each(|phase| weights(phase, &mut table));
+        table
+    });
+
+    let mut out = Vec::with_capacity(frames_out as usize * channels);
+    let mut acc = vec![0.0f64; channels];
+    let mut scratch = Vec::with_capacity(taps);
+    for n in 0..frames_out {
+        let (base, phase) = ((n * down / up) as i64, n * down % up);
+        let row = match &table {
+            Some(table) => &table[phase as usize * taps..][..taps],
+            None => {
+                scratch.clear();
+                weights(phase, &mut scratch);
+                &scratch[..]
+            }
+        };
+
+        acc.iter_mut().for_each(|a| *a = 0.0);
+        for (k, &w) in (base - reach..).zip(row) {
+            if w == 0.0 || k < 0 || k >= frames_in as i64 {
+                continue;
+            }
+            let k = k as usize;
+            let frame = &samples[k * channels..(k + 1) * channels];
+            for (a, &s) in acc.iter_mut().zip(frame) {
+                *a += s as f64 * w;
+            }
+        }
+        out.extend(acc.iter().map(|&a| a as f32));
+    }
+    out
+}
+
+/// The most fractional positions to precompute kernel weights for. Rates that share only a
+/// small common divisor, like 44.1 kHz and 47.999 kHz, are converted without a table.
+const MAX_PHASES: u64 = 1024;
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+fn kernel(x: f64, cutoff: f64, half_width: f64) -> f64 {
+    if x.abs() >= half_width {
+        return 0.0;
+    }
+    let window = 0.5 * (1.0 + (PI * x / half_width).cos());
+    let arg = PI * cutoff * x;
+    let sinc = if arg == 0.0 { 1.0 } else { arg.sin() / arg };
+    cutoff * sinc * window
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f32, rate: u32, seconds: f32) -> Vec<f32> {
+        let n = (rate as f32 * seconds) as usize;
+        (0..n)
+            .map(|i| 0.5 * (i as f32 * freq * std::f32::consts::TAU / rate as f32).sin())
+            .collect()
+    }
+
+    /// RMS of the middle of the signal, away from the edges the kernel can't fully see.
+    fn rms(samples: &[f32]) -> f32 {
+        let middle = &samples[samples.len() / 4..samples.len() * 3 / 4];
+        (middle.iter().map(|s| s * s).sum::<f32>() / middle.len() as f32).sqrt()
+    }
+
+    fn zero_crossings(samples: &[f32]) -> usize {
+        samples
+            .windows(2)
+            .filter(|w| (w[0] >= 0.0) != (w[1] >= 0.0))
+            .count()
+    }
+
+    #[test]
+    fn remix_to_mono_averages_channels() {
+        let stereo = [1.0, 0.0, 0.5, 0.5, -1.0, 1.0];
+        assert_eq!(remix(&stereo, 2, 1), vec![0.5, 0.5, 0.0]);
+        assert_eq!(remix(&[0.25, -0.25], 1, 2), vec![0.25, 0.25, -0.25, -0.25]);
+    }
+
+    #[test]
+    fn downsampling_keeps_pitch_and_level() {
+        let input = sine(440.0, 48_000, 1.0);
+        let output = resample(&input, 1, 48_000, 16_000);
+        assert_eq!(output.len(), 16_000);
+        // 440 Hz crosses zero 880 times a second
+        assert!((zero_crossings(&output) as i64 - 880).abs() <= 2);
+        assert!((rms(&output) - rms(&input)).abs() < 0.01);
+    }
+
+    #[test]
+    fn upsampling_keeps_pitch_and_level() {
+        let input = sine(1000.0, 8_000, 1.0);
+        let output = resample(&input, 1, 8_000, 16_000);
+        assert_eq!(output.len(), 16_000);
+        assert!((zero_crossings(&output) as i64 - 2000).abs() <= 2);
+        assert!((rms(&output) - rms(&input)).abs() < 0.01);
+    }
+
+    #[test]
+    fn downsampling_removes_frequencies_above_nyquist() {
+        // 12 kHz can't be represented at 16 kHz and must not alias down to 4 kHz
+        let input = sine(12_000.0, 48_000, 1.0);
+        let output = resample(&input, 1, 48_000, 16_000);
+        assert!(rms(&output) < 0.01, "rms {}", rms(&output));
+    }
+
+    #[test]
+    fn channels_are_resampled_independently() {
+        let left = sine(440.0, 44_100, 0.5);
+        let stereo: Vec<f32> = left.iter().flat_map(|&s| [s, 0.0]).collect();
+        let output = resample(&stereo, 2, 44_100, 16_000);
+        assert_eq!(output.len(), 8_000 * 2);
+        let right: Vec<f32> = output.iter().skip(1).step_by(2).copied().collect();
+        assert!(right.iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn rates_without_a_small_common_divisor() {
+        let input = sine(440.0, 44_100, 0.5);
+        let output = resample(&input, 1, 44_100, 47_999);
+        assert_eq!(output.len(), 24_000);
+        assert!((zero_crossings(&output) as i64 - 440).abs() <= 2);
+        assert!((rms(&output) - rms(&input)).abs() < 0.01);
+    }
+
+    #[test]
+    fn less_than_a_frame_is_nothing() {
+        assert!(resample(&[0.5], 2, 48_000, 16_000).is_empty());
+    }
+}
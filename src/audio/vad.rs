@@ -0,0 +1,135 @@
+//! Energy and zero-crossing voice activity detection, used to decide when a hands-free
+//! recording is over.
+
+use std::time::Duration;
+
+use crate::config;
+
+/// Length of the window each speech/silence decision is made on.
+const FRAME: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// Keep recording.
+    Listening,
+    /// The speaker went quiet for long enough, or never started.
+    Done,
+}
+
+pub struct Vad {
+    threshold_db: f32,
+    zero_crossing_rate: f32,
+    trailing_silence_frames: usize,
+    initial_silence_frames: usize,
+
+    channels: usize,
+    frame_len: usize,
+    /// interleaved samples not yet making up a whole frame
+    pending: Vec<f32>,
+
+    heard_speech: bool,
+    /// frames since the last speech frame, or since the start if there was none
+    silent_frames: usize,
+}
+
+impl Vad {
+    pub fn new(config: &config::Vad, sample_rate: u32, channels: u16) -> Self {
+        let frame_len = (sample_rate as u128 * FRAME.as_millis() / 1000) as usize;
+        let frames = |ms: u64| (ms / FRAME.as_millis() as u64) as usize;
+        Self {
+            threshold_db: config.threshold_db,
+            zero_crossing_rate: config.zero_crossing_rate,
+            trailing_silence_frames: frames(config.trailing_silence_ms),
+            initial_silence_frames: frames(config.initial_silence_ms),
+            channels: channels.max(1) as usize,
+            frame_len: frame_len.max(1),
+            pending: Vec::new(),
+            heard_speech: false,
+            silent_frames: 0,
+        }
+    }
+
+    /// Feed newly recorded interleaved samples.
+    pub fn push(&mut self, samples: &[f32]) -> Verdict {
+        self.pending.extend_from_slice(samples);
+        let stride = self.frame_len * self.channels;
+        let whole = self.pending.len() / stride * stride;
+        let frames: Vec<bool> = self.pending[..whole]
+            .chunks_exact(stride)
+            .map(|frame| self.is_speech(frame))
+            .collect();
+        self.pending.drain(..whole);
+
+        for speech in frames {
+            if speech {
+                self.heard_speech = true;
+                self.silent_frames = 0;
+            } else {
+                self.silent_frames += 1;
+            }
+        }
+        self.verdict()
+    }
+
+    fn verdict(&self) -> Verdict {
+        let limit = if self.heard_speech {
+            self.trailing_silence_frames
+        } else {
+            self.initial_silence_frames
+        };
+        if self.silent_frames >= limit {
+            Verdict::Done
+        } else {
+            Verdict::Listening
+        }
+    }
+
+    fn is_speech(&self, interleaved: &[f32]) -> bool {
+        let mono: Vec<f32> = interleaved
+            .chunks_exact(self.channels)
+            .map(|frame| frame.iter().sum::<f32>() / self.channels as f32)
+            .collect();
+
+        let energy = mono.iter().map(|s| s * s).sum::<f32>() / mono.len() as f32;
+        let db = 10.0 * energy.max(f32::MIN_POSITIVE).log10();
+        let crossings = mono
+            .windows(2)
+            .filter(|w| (w[0] >= 0.0) != (w[1] >= 0.0))
+            .count();
+        let zcr = crossings as f32 / mono.len() as f32;
+
+        db >= self.threshold_db
+            || (db >= self.threshold_db - 10.0 && zcr >= self.zero_crossing_rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 16_000;
+
+    fn tone(seconds: f32, amplitude: f32) -> Vec<f32> {
+        let n = (seconds * RATE as f32) as usize;
+        (0..n)
+            .map(|i| amplitude * (i as f32 * 220.0 * std::f32::consts::TAU / RATE as f32).sin())
+            .collect()
+    }
+
+    #[test]
+    fn stops_after_trailing_silence() {
+        let config = config::Vad::default();
+        let mut vad = Vad::new(&config, RATE, 1);
+        assert_eq!(vad.push(&tone(1.0, 0.3)), Verdict::Listening);
+        assert_eq!(vad.push(&tone(0.5, 0.0)), Verdict::Listening);
+        assert_eq!(vad.push(&tone(1.0, 0.0)), Verdict::Done);
+    }
+
+    #[test]
+    fn gives_up_when_nobody_speaks() {
+        let config = config::Vad::default();
+        let mut vad = Vad::new(&config, RATE, 1);
+        assert_eq!(vad.push(&tone(4.0, 0.001)), Verdict::Listening);
+        assert_eq!(vad.push(&tone(2.0, 0.001)), Verdict::Done);
+    }
+}
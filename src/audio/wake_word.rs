@@ -0,0 +1,390 @@
+//! A small keyword spotter for the wake word. Incoming audio is turned into MFCC frames and
+//! compared, with dynamic time warping, against the frames of a few enrolled examples. It is
+//! crude next to a trained model, but needs no model, no network and very little CPU.
+
+use std::{collections::VecDeque, f32::consts::PI, sync::Arc, time::Duration};
+
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+use xdg::BaseDirectories;
+
+use super::{from_wav, resample, Clip};
+use crate::config;
+use crate::consts::PROJECT_NAME;
+
+const FRAME: Duration = Duration::from_millis(25);
+const HOP: Duration = Duration::from_millis(10);
+const MEL_BANDS: usize = 26;
+/// Cepstral coefficients 1 through 12. Coefficient 0 is left out since it only tracks
+/// loudness, and the wake word should match however loudly it is said.
+const COEFFICIENTS: usize = 12;
+const LOWEST_HZ: f32 = 100.0;
+const HIGHEST_HZ: f32 = 7600.0;
+/// How many new frames to collect between comparisons.
+const CHECK_EVERY: usize = 10;
+
+type Coefficients = [f32; COEFFICIENTS];
+
+struct Frame {
+    coefficients: Coefficients,
+    /// RMS level, in dBFS
+    db: f32,
+}
+
+/// Turns mono audio into MFCC frames.
+struct Features {
+    frame_len: usize,
+    hop: usize,
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    /// for each mel band, the FFT bins it covers and their weights
+    bands: Vec<Vec<(usize, f32)>>,
+    /// samples not yet consumed by a whole hop
+    pending: Vec<f32>,
+}
+
+impl Features {
+    fn new(sample_rate: u32) -> Self {
+        let samples = |d: Duration| (sample_rate as f64 * d.as_secs_f64()) as usize;
+        let frame_len = samples(FRAME).max(1);
+        let fft_len = frame_len.next_power_of_two();
+        let window = (0..frame_len)
+            .map(|n| 0.54 - 0.46 * (2.0 * PI * n as f32 / (frame_len - 1).max(1) as f32).cos())
+            .collect();
+
+        let mel = |hz: f32| 2595.0 * (1.0 + hz / 700.0).log10();
+        let highest = HIGHEST_HZ.min(sample_rate as f32 / 2.0);
+        let edges: Vec<f32> = (0..MEL_BANDS + 2)
+            .map(|i| {
+                mel(LOWEST_HZ) + (mel(highest) - mel(LOWEST_HZ)) * i as f32 / (MEL_BANDS + 1) as f32
+            })
+            .collect();
+        let bands = edges
+            .windows(3)
+            .map(|edge| {
+                (0..=fft_len / 2)
+                    .filter_map(|bin| {
+                        let m = mel(bin as f32 * sample_rate as f32 / fft_len as f32);
+                        let weight = if m < edge[1] {
+                            (m - edge[0]) / (edge[1] - edge[0])
+                        } else {
+                            (edge[2] - m) / (edge[2] - edge[1])
+                        };
+                        (weight > 0.0).then_some((bin, weight))
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Self {
+            frame_len,
+            hop: samples(HOP).max(1),
+            fft: FftPlanner::new().plan_fft_forward(fft_len),
+            window,
+            bands,
+            pending: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, mono: &[f32], frames: &mut Vec<Frame>) {
+        self.pending.extend_from_slice(mono);
+        let mut start = 0;
+        while start + self.frame_len <= self.pending.len() {
+            frames.push(self.frame(&self.pending[start..start + self.frame_len]));
+            start += self.hop;
+        }
+        self.pending.drain(..start);
+    }
+
+    fn frame(&self, samples: &[f32]) -> Frame {
+        let power = samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32;
+
+        let mut spectrum = vec![Complex::default(); self.fft.len()];
+        for ((bin, sample), w) in spectrum.iter_mut().zip(samples).zip(&self.window) {
+            bin.re = sample * w;
+        }
+        self.fft.process(&mut spectrum);
+
+        let energies: Vec<f32> = self
+            .bands
+            .iter()
+            .map(|band| {
+                let energy: f32 = band
+                    .iter()
+                    .map(|&(bin, w)| w * spectrum[bin].norm_sqr())
+                    .sum();
+                energy.max(1e-10).ln()
+            })
+            .collect();
+        let mut coefficients = [0.0; COEFFICIENTS];
+        for (i, c) in coefficients.iter_mut().enumerate() {
+            *c = energies
+                .iter()
+                .enumerate()
+                .map(|(b, e)| e * (PI * (i + 1) as f32 * (b as f32 + 0.5) / MEL_BANDS as f32).cos())
+                .sum();
+        }
+
+        Frame {
+            coefficients,
+            db: 10.0 * power.max(f32::MIN_POSITIVE).log10(),
+        }
+    }
+}
+
+fn distance(a: &Coefficients, b: &Coefficients) -> f32 {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (a - b) * (a - b))
+        .sum::<f32>()
+        .sqrt()
+}
+
+/// Average per-frame distance of the best alignment of all of `template` with some stretch of
+/// `heard`, which may start and end anywhere.
+fn match_cost(template: &[Coefficients], heard: &[Coefficients]) -> f32 {
+    if template.is_empty() || heard.is_empty() {
+        return f32::INFINITY;
+    }
+    // one row of the cost matrix at a time, rows following the template
+    let mut previous: Vec<f32> = heard.iter().map(|h| distance(&template[0], h)).collect();
+    let mut current = vec![0.0; heard.len()];
+    for t in &template[1..] {
+        for (j, h) in heard.iter().enumerate() {
+            let best = match j {
+                0 => previous[0],
+                _ => previous[j].min(previous[j - 1]).min(current[j - 1]),
+            };
+            current[j] = distance(t, h) + best;
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous.iter().copied().fold(f32::INFINITY, f32::min) / template.len() as f32
+}
+
+/// Listens for the wake word in the audio heard between questions.
+pub struct WakeWord {
+    channels: u16,
+    sample_rate: u32,
+    features: Features,
+    templates: Vec<Vec<Coefficients>>,
+    /// matches costing less than this count as the wake word
+    threshold: f32,
+    /// frames quieter than this are skipped, both in templates and in what is heard
+    gate_db: f32,
+    recent: VecDeque<Frame>,
+    capacity: usize,
+    since_check: usize,
+}
+
+impl WakeWord {
+    /// Load the enrolled examples from `$XDG_CONFIG_HOME/ushidashi/wake_word/`.
+    pub fn load(
+        config: &config::WakeWord,
+        vad: &config::Vad,
+        sample_rate: u32,
+        channels: u16,
+    ) -> anyhow::Result<Self> {
+        let base = BaseDirectories::with_prefix(PROJECT_NAME)?;
+        let mut examples = Vec::new();
+        for path in base.list_config_files("wake_word") {
+            if path.extension().is_some_and(|ext| ext == "wav") {
+                let wav = std::fs::read(&path)?;
+                let clip =
+                    from_wav(&wav).map_err(|e| anyhow::anyhow!("Failed to read {path:?}: {e}"))?;
+                examples.push((path, clip));
+            }
+        }
+        let mut spotter = Self::new(vad, sample_rate, channels);
+        for (path, clip) in examples {
+            spotter
+                .enroll(&clip)
+                .map_err(|e| anyhow::anyhow!("Wake word example {path:?}: {e}"))?;
+        }
+        spotter.calibrate(config.sensitivity)?;
+        eprintln!(
+            "listening for the wake word, {} examples enrolled",
+            spotter.templates.len()
+        );
+        Ok(spotter)
+    }
+
+    fn new(vad: &config::Vad, sample_rate: u32, channels: u16) -> Self {
+        Self {
+            channels,
+            sample_rate,
+            features: Features::new(sample_rate),
+            templates: Vec::new(),
+            threshold: 0.0,
+            gate_db: vad.threshold_db,
+            recent: VecDeque::new(),
+            capacity: 0,
+            since_check: 0,
+        }
+    }
+
+    /// Add an example of the wake word, with the silence around it trimmed off.
+    fn enroll(&mut self, clip: &Clip) -> anyhow::Result<()> {
+        let mono = resample::remix(&clip.samples, clip.channels, 1);
+        let mut frames = Vec::new();
+        Features::new(clip.sample_rate).push(&mono, &mut frames);
+        let first = frames.iter().position(|f| f.db >= self.gate_db);
+        let last = frames.iter().rposition(|f| f.db >= self.gate_db);
+        let (Some(first), Some(last)) = (first, last) else {
+            anyhow::bail!("nothing louder than {} dBFS in it", self.gate_db);
+        };
+        let template: Vec<_> = frames[first..=last]
+            .iter()
+            .map(|f| f.coefficients)
+            .collect();
+        // room for the longest example said at half speed
+        self.capacity = self.capacity.max(template.len() * 2);
+        self.templates.push(template);
+        Ok(())
+    }
+
+    /// Set the threshold from how closely the examples match one another, so it suits the
+    /// speaker and the microphone rather than being a magic number.
+    fn calibrate(&mut self, sensitivity: f32) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.templates.len() >= 2,
+            "The wake word needs at least two examples to compare, found {}. Record some with \
+             `ushidashi enroll`.",
+            self.templates.len()
+        );
+        let mut costs = Vec::new();
+        for (i, a) in self.templates.iter().enumerate() {
+            for (j, b) in self.templates.iter().enumerate() {
+                if i != j {
+                    costs.push(match_cost(a, b));
+                }
+            }
+        }
+        let typical = costs.iter().sum::<f32>() / costs.len() as f32;
+        self.threshold = typical * (0.5 + sensitivity.clamp(0.0, 1.0));
+        Ok(())
+    }
+
+    /// Forget what was heard so far, so audio from before a question can't complete a match
+    /// after it, and expect audio in this format from now on.
+    pub fn reset(&mut self, sample_rate: u32, channels: u16) {
+        if sample_rate != self.sample_rate {
+            self.features = Features::new(sample_rate);
+            self.sample_rate = sample_rate;
+        }
+        self.channels = channels;
+        self.features.pending.clear();
+        self.recent.clear();
+        self.since_check = 0;
+    }
+
+    /// The sample rate and channel count audio is expected in.
+    pub fn format(&self) -> (u32, u16) {
+        (self.sample_rate, self.channels)
+    }
+
+    /// Feed in interleaved audio. Returns true once the wake word has been heard.
+    pub fn push(&mut self, samples: &[f32]) -> bool {
+        let mono = resample::remix(samples, self.channels, 1);
+        let mut frames = Vec::new();
+        self.features.push(&mono, &mut frames);
+        for frame in frames {
+            self.recent.push_back(frame);
+            if self.recent.len() > self.capacity {
+                self.recent.pop_front();
+            }
+            self.since_check += 1;
+        }
+        if self.since_check < CHECK_EVERY {
+            return false;
+        }
+        self.since_check = 0;
+
+        // nothing but quiet, so there is nothing to compare
+        if self.recent.iter().all(|f| f.db < self.gate_db) {
+            return false;
+        }
+        let heard: Vec<_> = self.recent.iter().map(|f| f.coefficients).collect();
+        let best = self
+            .templates
+            .iter()
+            .map(|template| match_cost(template, &heard))
+            .fold(f32::INFINITY, f32::min);
+        if best < self.threshold {
+            self.reset(self.sample_rate, self.channels);
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 16_000;
+
+    /// A vowel-like buzz gliding between two pitches, with a little noise so no two takes
+    /// are identical.
+    fn glide(from: f32, to: f32, seconds: f32, seed: u32) -> Vec<f32> {
+        let mut state = seed;
+        let len = (RATE as f32 * seconds) as usize;
+        let mut phase = 0.0f32;
+        (0..len)
+            .map(|n| {
+                let f = from + (to - from) * n as f32 / len as f32;
+                phase += 2.0 * PI * f / RATE as f32;
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                let noise = (state >> 16) as f32 / 65_536.0 - 0.5;
+                0.3 * (phase.sin() + 0.5 * (2.0 * phase).sin() + 0.25 * (3.0 * phase).sin())
+                    + 0.01 * noise
+            })
+            .collect()
+    }
+
+    fn clip(samples: Vec<f32>) -> Clip {
+        Clip {
+            samples,
+            channels: 1,
+            sample_rate: RATE,
+        }
+    }
+
+    fn enrolled() -> WakeWord {
+        let mut spotter = WakeWord::new(&config::Vad::default(), RATE, 1);
+        // said a little faster or slower each time
+        for (seed, seconds) in [(1, 0.5), (2, 0.6), (3, 0.75)] {
+            spotter
+                .enroll(&clip(glide(300.0, 900.0, seconds, seed)))
+                .unwrap();
+        }
+        spotter.calibrate(0.5).unwrap();
+        spotter
+    }
+
+    /// Feed audio in 20 ms chunks, the way it arrives from the microphone.
+    fn hears(spotter: &mut WakeWord, samples: &[f32]) -> bool {
+        let mut heard = false;
+        for chunk in samples.chunks(RATE as usize / 50) {
+            heard |= spotter.push(chunk);
+        }
+        heard
+    }
+
+    #[test]
+    fn spots_the_word_and_nothing_else() {
+        let silence = vec![0.0; RATE as usize / 2];
+
+        let mut spotter = enrolled();
+        let mut word = silence.clone();
+        word.extend(glide(300.0, 900.0, 0.7, 42));
+        word.extend(&silence);
+        assert!(hears(&mut spotter, &word));
+
+        let mut spotter = enrolled();
+        let mut other = silence.clone();
+        other.extend(glide(900.0, 200.0, 0.7, 42));
+        other.extend(&silence);
+        assert!(!hears(&mut spotter, &other));
+        assert!(!hears(&mut spotter, &silence));
+    }
+}
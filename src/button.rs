@@ -1,67 +1,450 @@
 #![allow(unused)]
 
-pub use b::Button;
+mod debounce;
+mod filter;
+mod gesture;
+mod gpio;
+mod key;
+#[cfg(feature = "emulate")]
+mod screen;
+
+pub use filter::print_keys;
+pub use gesture::{Gesture, Recognizer};
+
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use debounce::Debounce;
+
+use crate::config;
+
+/// The button, or one button per speaker when speakers are configured. The backends push what
+/// they see as it happens, and presses and releases come out of [`Button::next`].
+pub struct Button {
+    /// kept for as long as the button is, since some backends stop when dropped
+    backend: b::Backend,
+    changes: UnboundedReceiver<Change>,
+    /// which buttons are down, as of the changes received so far
+    down: Vec<bool>,
+    /// who each of the backend's buttons belongs to, in the same order
+    speakers: Vec<Option<String>>,
+}
+
+/// A press or release of one of the buttons.
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub pressed: bool,
+    /// whose button it was, if it belongs to someone
+    pub speaker: Option<String>,
+    pub at: SystemTime,
+}
+
+/// A debounced change of one of the buttons.
+struct Change {
+    button: usize,
+    pressed: bool,
+    at: SystemTime,
+}
+
+/// What one source, such as one input device, says about one of the buttons, before
+/// debouncing.
+pub struct Raw {
+    pub source: usize,
+    pub button: usize,
+    pub down: bool,
+    pub at: SystemTime,
+}
+
+/// A number for a new source of [`Raw`] changes.
+fn new_source() -> usize {
+    static SOURCES: AtomicUsize = AtomicUsize::new(0);
+    SOURCES.fetch_add(1, Ordering::Relaxed)
+}
+
+impl Button {
+    /// Start reading the configured buttons. This has to be called within the tokio runtime.
+    pub fn create(button: &config::Button) -> anyhow::Result<Self> {
+        let parts = parts(button);
+        let (raw, raw_changes) = unbounded_channel();
+        let (changes_tx, changes) = unbounded_channel();
+        let backend = b::Backend::create(button, &parts, raw)?;
+        tokio::spawn(debounce(
+            raw_changes,
+            changes_tx,
+            parts.len(),
+            Duration::from_millis(button.debounce_ms),
+        ));
+        Ok(Self {
+            backend,
+            changes,
+            down: vec![false; parts.len()],
+            speakers: parts.into_iter().map(|part| part.speaker).collect(),
+        })
+    }
+
+    /// Wait for the next press or release. `None` once the buttons can no longer be read.
+    pub async fn next(&mut self) -> Option<Event> {
+        match self.changes.recv().await {
+            Some(change) => Some(self.apply(change)),
+            None => {
+                self.down.fill(false);
+                None
+            }
+        }
+    }
+
+    /// Take in the changes that came while nobody was waiting for them, so [`Self::pressed`]
+    /// is up to date and [`Self::next`] only returns what happens from now on.
+    pub fn catch_up(&mut self) {
+        while let Ok(change) = self.changes.try_recv() {
+            self.apply(change);
+        }
+    }
+
+    fn apply(&mut self, change: Change) -> Event {
+        self.down[change.button] = change.pressed;
+        Event {
+            pressed: change.pressed,
+            speaker: self.speakers[change.button].clone(),
+            at: change.at,
+        }
+    }
+
+    /// Whether any of the buttons is down, as of the last event taken.
+    pub fn pressed(&self) -> bool {
+        self.down.contains(&true)
+    }
+
+    /// Show the toy's state and captions alongside the button, where the backend has a
+    /// screen to show them on.
+    pub fn mirror(&self, display: crate::indicator::Display) {
+        self.backend.mirror(display);
+    }
+
+    /// Whose button is down, if it belongs to someone.
+    pub fn speaker(&self) -> Option<&str> {
+        self.down
+            .iter()
+            .zip(&self.speakers)
+            .find(|&(&down, _)| down)
+            .and_then(|(_, speaker)| speaker.as_deref())
+    }
+}
+
+/// Merge what the sources say about each button, filter out contact bounce and pass on the
+/// presses and releases that are left. A bounce that settles in a new state only takes effect
+/// once the debounce window is over, so this also wakes up then.
+async fn debounce(
+    mut raw: UnboundedReceiver<Raw>,
+    changes: UnboundedSender<Change>,
+    buttons: usize,
+    window: Duration,
+) {
+    let mut sources = HashMap::new();
+    let mut states: Vec<Debounce> = (0..buttons).map(|_| Debounce::new(window)).collect();
+    let mut reported = vec![false; buttons];
+    loop {
+        let settles = states.iter().filter_map(Debounce::settles_at).min();
+        let wait = settles.map(|at| at.duration_since(SystemTime::now()).unwrap_or_default());
+        let at = tokio::select! {
+            change = raw.recv() => {
+                let Some(change) = change else { return };
+                sources.insert((change.source, change.button), change.down);
+                let down = sources
+                    .iter()
+                    .any(|(&(_, button), &down)| button == change.button && down);
+                states[change.button].update(down, change.at);
+                change.at
+            }
+            () = tokio::time::sleep(wait.unwrap_or_default()), if wait.is_some() => {
+                SystemTime::now()
+            }
+        };
+        let now = SystemTime::now().max(at);
+        for (button, (state, reported)) in states.iter_mut().zip(&mut reported).enumerate() {
+            state.settle(now);
+            let pressed = state.pressed(now);
+            if pressed != *reported {
+                *reported = pressed;
+                if changes
+                    .send(Change {
+                        button,
+                        pressed,
+                        at,
+                    })
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// One of the buttons, as the backends need it.
+struct Part {
+    speaker: Option<String>,
+    keys: Vec<String>,
+    line: Option<u32>,
+}
+
+/// The buttons to listen to: one per speaker or, when there are no speakers, a single button
+/// made of the shared keys and line that belongs to nobody in particular.
+fn parts(button: &config::Button) -> Vec<Part> {
+    if button.speakers.is_empty() {
+        return vec![Part {
+            speaker: None,
+            keys: button.keys.clone(),
+            line: Some(button.gpio.line),
+        }];
+    }
+    button
+        .speakers
+        .iter()
+        .map(|speaker| Part {
+            speaker: Some(speaker.name.clone()),
+            keys: speaker.keys.clone(),
+            line: speaker.line,
+        })
+        .collect()
+}
 
 #[cfg(not(feature = "emulate"))]
 mod b {
     use std::{
-        sync::{atomic::AtomicBool, Arc},
+        collections::HashMap,
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex},
         thread::{self, JoinHandle},
+        time::SystemTime,
     };
 
     use evdev::{enumerate, Device};
+    use inotify::{EventMask, Inotify, WatchMask};
+    use tokio::sync::mpsc::UnboundedSender;
+
+    use super::filter::{parse_keys, Identity};
+    use super::gpio::GpioButton;
+    use super::key::KeyTracker;
+    use super::{new_source, Part, Raw};
+    use crate::config;
+
+    /// Where device nodes come and go as keyboards are plugged in and out.
+    const INPUT_DIR: &str = "/dev/input";
+
+    /// Every attached device, by device node, with the thread following its keys.
+    type Watchers = Arc<Mutex<HashMap<PathBuf, JoinHandle<()>>>>;
 
-    fn find_keyboard_devices() -> Vec<Device> {
-        enumerate()
-            .map(|(_, dev)| dev)
-            .filter(|dev| dev.supported_events().contains(evdev::EventType::KEY))
-            .collect()
+    /// The buttons, read by the configured backend.
+    pub enum Backend {
+        Keys(Keys),
+        Gpio(Vec<GpioButton>),
     }
 
-    pub struct Button {
-        threads: Vec<(JoinHandle<()>, Arc<AtomicBool>)>,
+    /// The configured keys on the configured input devices, including devices plugged in
+    /// after startup.
+    pub struct Keys {
+        watchers: Watchers,
+        /// keeps the button open while no devices are attached, since one may yet be
+        /// plugged in
+        raw: UnboundedSender<Raw>,
     }
 
-    fn watch_key(mut dev: Device, pressed: Arc<AtomicBool>) {
+    /// What to listen for, shared with the hotplug thread.
+    struct Settings {
+        /// the keys of each button
+        keys: Vec<Vec<evdev::Key>>,
+        devices: Vec<config::DeviceFilter>,
+        raw: UnboundedSender<Raw>,
+    }
+
+    fn watch_key(mut dev: Device, mut trackers: Vec<KeyTracker>, raw: UnboundedSender<Raw>) {
+        let name = dev.name().unwrap_or("unnamed device").to_owned();
+        let source = new_source();
         loop {
-            for event in dev.fetch_events().unwrap() {
-                match event.kind() {
-                    evdev::InputEventKind::Key(k) => {
-                        if k == evdev::Key::KEY_SPACE {
-                            pressed.store(true, std::sync::atomic::Ordering::Relaxed);
-                        }
+            let events = match dev.fetch_events() {
+                Ok(events) => events,
+                Err(e) => {
+                    eprintln!("Stopped reading keys from {name}: {e}");
+                    break;
+                }
+            };
+            for event in events {
+                for (button, tracker) in trackers.iter_mut().enumerate() {
+                    if let Some(down) = tracker.handle(&event) {
+                        let at = event.timestamp();
+                        let _ = raw.send(Raw {
+                            source,
+                            button,
+                            down,
+                            at,
+                        });
                     }
-                    _ => {}
                 }
             }
         }
+        // a device that went away can't be holding a button down
+        for (button, tracker) in trackers.iter().enumerate() {
+            if tracker.pressed() {
+                let _ = raw.send(Raw {
+                    source,
+                    button,
+                    down: false,
+                    at: SystemTime::now(),
+                });
+            }
+        }
     }
 
-    // may need to update this later to support hotplugging
-    impl Button {
-        pub fn create() -> Self {
-            let devices = find_keyboard_devices();
-            eprintln!("Found {} keyboard devices", devices.len());
-            let threads = devices
-                .into_iter()
-                .map(|device| {
-                    let pressed = Arc::new(AtomicBool::new(false));
-                    let pressed_c = pressed.clone();
-                    let th = thread::spawn(move || {
-                        watch_key(device, pressed_c);
-                    });
-                    (th, pressed)
-                })
-                .collect();
-            Self { threads }
+    fn is_attached(watchers: &Watchers, path: &Path) -> bool {
+        let watchers = watchers.lock().unwrap();
+        watchers
+            .get(path)
+            .is_some_and(|thread| !thread.is_finished())
+    }
+
+    /// Start following a device, if it has keys and passes the filters. Returns whether it
+    /// was attached.
+    fn attach(watchers: &Watchers, path: PathBuf, device: Device, settings: &Settings) -> bool {
+        if !device.supported_events().contains(evdev::EventType::KEY)
+            || !Identity::of(&device).allowed_by(&settings.devices)
+        {
+            return false;
         }
+        let trackers = settings
+            .keys
+            .iter()
+            .map(|keys| KeyTracker::new(keys.clone()))
+            .collect();
+        let raw = settings.raw.clone();
+        let thread = thread::spawn(move || {
+            watch_key(device, trackers, raw);
+        });
+        watchers.lock().unwrap().insert(path, thread);
+        true
+    }
 
-        pub fn pressed(&self) -> Option<bool> {
-            Some(
-                self.threads
+    /// Attach devices as they appear in [`INPUT_DIR`] and forget them as they go.
+    fn watch_hotplug(mut inotify: Inotify, watchers: Watchers, settings: Settings) {
+        let mut buffer = [0; 4096];
+        loop {
+            let events = match inotify.read_events_blocking(&mut buffer) {
+                Ok(events) => events,
+                Err(e) => {
+                    eprintln!("Stopped watching {INPUT_DIR} for new devices: {e}");
+                    return;
+                }
+            };
+            for event in events {
+                let Some(name) = event.name else { continue };
+                if !name.to_string_lossy().starts_with("event") {
+                    continue;
+                }
+                let path = Path::new(INPUT_DIR).join(name);
+                if event.mask.contains(EventMask::DELETE) {
+                    if watchers.lock().unwrap().remove(&path).is_some() {
+                        eprintln!("{path:?} was unplugged");
+                    }
+                    continue;
+                }
+                if is_attached(&watchers, &path) {
+                    continue;
+                }
+                // the node appears before udev makes it readable, so this may fail on
+                // creation and work on a later change of permissions
+                if let Ok(device) = Device::open(&path) {
+                    let name = device.name().unwrap_or("unnamed device").to_owned();
+                    if attach(&watchers, path.clone(), device, &settings) {
+                        eprintln!("{name} was plugged in at {path:?}");
+                    }
+                }
+            }
+        }
+    }
+
+    impl Backend {
+        /// Start following the buttons, sending what they do to `raw`.
+        pub fn create(
+            button: &config::Button,
+            parts: &[Part],
+            raw: UnboundedSender<Raw>,
+        ) -> anyhow::Result<Self> {
+            Ok(match button.backend {
+                config::ButtonBackend::Evdev => Self::Keys(Keys::create(button, parts, raw)?),
+                config::ButtonBackend::Gpio => Self::Gpio(
+                    parts
+                        .iter()
+                        .enumerate()
+                        .map(|(i, part)| {
+                            let line = part.line.ok_or_else(|| {
+                                anyhow::anyhow!(
+                                    "{} has no GPIO line",
+                                    part.speaker.as_deref().unwrap_or("the button")
+                                )
+                            })?;
+                            let gpio = config::Gpio {
+                                line,
+                                ..button.gpio.clone()
+                            };
+                            GpioButton::open(&gpio, i, raw.clone())
+                        })
+                        .collect::<anyhow::Result<_>>()?,
+                ),
+            })
+        }
+
+        /// The buttons here have nowhere to show anything.
+        pub fn mirror(&self, _display: crate::indicator::Display) {}
+    }
+
+    impl Keys {
+        fn create(
+            button: &config::Button,
+            parts: &[Part],
+            raw: UnboundedSender<Raw>,
+        ) -> anyhow::Result<Self> {
+            let settings = Settings {
+                keys: parts
                     .iter()
-                    .any(|(_, pressed)| pressed.load(std::sync::atomic::Ordering::Relaxed)),
-            )
+                    .map(|part| parse_keys(&part.keys))
+                    .collect::<anyhow::Result<_>>()?,
+                devices: button.devices.clone(),
+                raw: raw.clone(),
+            };
+            let watchers = Watchers::default();
+
+            // start watching before enumerating, so a device plugged in between the two
+            // isn't missed
+            let inotify = Inotify::init().and_then(|inotify| {
+                inotify.watches().add(
+                    INPUT_DIR,
+                    WatchMask::CREATE | WatchMask::ATTRIB | WatchMask::DELETE,
+                )?;
+                Ok(inotify)
+            });
+            for (path, device) in enumerate() {
+                attach(&watchers, path, device, &settings);
+            }
+            eprintln!(
+                "Found {} input devices to listen on",
+                watchers.lock().unwrap().len()
+            );
+            match inotify {
+                Ok(inotify) => {
+                    let watchers = watchers.clone();
+                    thread::spawn(move || watch_hotplug(inotify, watchers, settings));
+                }
+                Err(e) => eprintln!(
+                    "Can't watch {INPUT_DIR} for new devices, only those present now will work: {e}"
+                ),
+            }
+
+            Ok(Self { watchers, raw })
         }
     }
 }
@@ -69,70 +452,200 @@ mod b {
 #[cfg(feature = "emulate")]
 mod b {
     use std::{
-        sync::{
-            atomic::{self, AtomicBool},
-            Arc,
-        },
+        sync::{Arc, Mutex},
         thread,
+        time::SystemTime,
     };
 
-    use miniquad::{conf::Conf, Context, EventHandler, KeyCode, KeyMods};
+    use embedded_graphics::prelude::Point;
+    use miniquad::{conf::Conf, Context, EventHandler, KeyCode, KeyMods, MouseButton};
+    use tokio::sync::mpsc::UnboundedSender;
 
-    /// Button emulator. The button is displayed as a window. Holding down space while the
-    /// window is in focus is equivalent to pressing the button.
-    pub struct Button {
-        pressed: Arc<AtomicBool>,
+    use super::{
+        new_source,
+        screen::{self, Canvas, Gpu, Scene},
+        Part, Raw,
+    };
+    use crate::{
+        config,
+        indicator::{Captions, Display, State},
+    };
+
+    /// Keys for the speakers' buttons, in order.
+    const SPEAKER_KEYS: [KeyCode; 9] = [
+        KeyCode::Key1,
+        KeyCode::Key2,
+        KeyCode::Key3,
+        KeyCode::Key4,
+        KeyCode::Key5,
+        KeyCode::Key6,
+        KeyCode::Key7,
+        KeyCode::Key8,
+        KeyCode::Key9,
+    ];
 
+    /// Button emulator. The button is displayed as a window. Holding down space while the
+    /// window is in focus, or holding the button down with the mouse, is equivalent to
+    /// pressing the button. With speakers configured, there is a button for each, and the
+    /// number keys press them in order.
+    pub struct Backend {
         /// the worker that is running the window
         thread: thread::JoinHandle<()>,
+        /// what the window shows besides the buttons, once there is something to show
+        display: Arc<Mutex<Option<Display>>>,
     }
 
-    impl Button {
-        pub fn create() -> Self {
-            let pressed = Arc::new(AtomicBool::new(false));
-            let pressed_clone = pressed.clone();
+    impl Backend {
+        /// The emulator always uses the keys above, so the button settings have nothing to
+        /// change here. Closing the window closes the button.
+        pub fn create(
+            _button: &config::Button,
+            parts: &[Part],
+            raw: UnboundedSender<Raw>,
+        ) -> anyhow::Result<Self> {
+            let (keys, labels) = if parts.iter().all(|part| part.speaker.is_none()) {
+                (vec![KeyCode::Space], vec!["space".to_owned()])
+            } else {
+                anyhow::ensure!(
+                    parts.len() <= SPEAKER_KEYS.len(),
+                    "The emulator has keys for at most {} speakers",
+                    SPEAKER_KEYS.len()
+                );
+                let labels = parts
+                    .iter()
+                    .enumerate()
+                    .map(|(i, part)| match &part.speaker {
+                        Some(speaker) => format!("{}: {speaker}", i + 1),
+                        None => format!("{}", i + 1),
+                    })
+                    .collect();
+                (SPEAKER_KEYS[..parts.len()].to_vec(), labels)
+            };
+            let display = Arc::new(Mutex::new(None));
 
-            let thread = thread::spawn(move || {
-                AppState {
-                    pressed: pressed_clone,
-                }
-                .run();
-            });
+            let thread = {
+                let display = display.clone();
+                thread::spawn(move || run(keys, labels, display, raw))
+            };
 
-            Button { pressed, thread }
+            Ok(Backend { thread, display })
         }
 
-        pub fn pressed(&self) -> Option<bool> {
-            if self.thread.is_finished() {
-                None
-            } else {
-                Some(self.pressed.load(atomic::Ordering::Relaxed))
-            }
+        pub fn mirror(&self, display: Display) {
+            *self.display.lock().unwrap() = Some(display);
         }
     }
 
+    fn run(
+        keys: Vec<KeyCode>,
+        labels: Vec<String>,
+        display: Arc<Mutex<Option<Display>>>,
+        raw: UnboundedSender<Raw>,
+    ) {
+        miniquad::start(
+            Conf {
+                window_title: "Ushidashi".to_owned(),
+                window_width: screen::WIDTH as i32,
+                window_height: screen::HEIGHT as i32,
+                ..Default::default()
+            },
+            move |ctx| {
+                let down = vec![false; keys.len()];
+                Box::new(AppState {
+                    keys,
+                    down,
+                    source: new_source(),
+                    mouse_source: new_source(),
+                    mouse_held: None,
+                    raw,
+                    labels,
+                    display,
+                    shown: None,
+                    canvas: Canvas::new(),
+                    gpu: Gpu::new(ctx),
+                })
+            },
+        );
+    }
+
     struct AppState {
-        pressed: Arc<AtomicBool>,
+        keys: Vec<KeyCode>,
+        /// which of the keys are down, to leave out auto-repeat
+        down: Vec<bool>,
+        source: usize,
+        /// the mouse is a source of its own, so it and the keys can hold a button together
+        mouse_source: usize,
+        /// the button the mouse is holding down
+        mouse_held: Option<usize>,
+        raw: UnboundedSender<Raw>,
+        labels: Vec<String>,
+        display: Arc<Mutex<Option<Display>>>,
+        /// what the canvas holds, so it is only drawn again when that changes
+        shown: Option<Scene>,
+        canvas: Canvas,
+        gpu: Gpu,
     }
 
     impl AppState {
-        fn run(self) {
-            miniquad::start(
-                Conf {
-                    window_title: "Spacebar Window".to_owned(),
-                    window_width: 800,
-                    window_height: 600,
-                    ..Default::default()
-                },
-                |_| Box::new(self),
-            );
+        fn set(&mut self, keycode: KeyCode, down: bool) {
+            let Some(button) = self.keys.iter().position(|&key| key == keycode) else {
+                return;
+            };
+            if self.down[button] != down {
+                self.down[button] = down;
+                self.send(self.source, button, down);
+            }
+        }
+
+        fn send(&self, source: usize, button: usize, down: bool) {
+            let _ = self.raw.send(Raw {
+                source,
+                button,
+                down,
+                at: SystemTime::now(),
+            });
+        }
+
+        fn scene(&self) -> Scene {
+            let buttons = self
+                .labels
+                .iter()
+                .enumerate()
+                .map(|(i, label)| (label.clone(), self.down[i] || self.mouse_held == Some(i)))
+                .collect();
+            let (state, captions) = match &*self.display.lock().unwrap() {
+                Some(display) => (*display.state.borrow(), display.captions.borrow().clone()),
+                None => (State::Idle, Captions::default()),
+            };
+            Scene {
+                state,
+                buttons,
+                captions,
+            }
+        }
+
+        /// Where a point in the window falls on the canvas, which is stretched over it.
+        fn on_canvas(ctx: &Context, x: f32, y: f32) -> Point {
+            let (width, height) = ctx.screen_size();
+            Point::new(
+                (x * screen::WIDTH as f32 / width) as i32,
+                (y * screen::HEIGHT as f32 / height) as i32,
+            )
         }
     }
 
     impl EventHandler for AppState {
         fn update(&mut self, _ctx: &mut Context) {}
 
-        fn draw(&mut self, _ctx: &mut Context) {}
+        fn draw(&mut self, ctx: &mut Context) {
+            let scene = self.scene();
+            if self.shown.as_ref() != Some(&scene) {
+                screen::render(&scene, &mut self.canvas);
+                self.gpu.upload(ctx, &self.canvas);
+                self.shown = Some(scene);
+            }
+            self.gpu.draw(ctx);
+        }
 
         fn key_down_event(
             &mut self,
@@ -141,14 +654,44 @@ mod b {
             _keymods: KeyMods,
             _repeat: bool,
         ) {
-            if keycode == KeyCode::Space {
-                self.pressed.store(true, atomic::Ordering::Relaxed);
-            }
+            self.set(keycode, true);
         }
 
         fn key_up_event(&mut self, _ctx: &mut Context, keycode: KeyCode, _keymods: KeyMods) {
-            if keycode == KeyCode::Space {
-                self.pressed.store(false, atomic::Ordering::Relaxed);
+            self.set(keycode, false);
+        }
+
+        fn mouse_button_down_event(
+            &mut self,
+            ctx: &mut Context,
+            mouse_button: MouseButton,
+            x: f32,
+            y: f32,
+        ) {
+            if mouse_button != MouseButton::Left || self.mouse_held.is_some() {
+                return;
+            }
+            let at = Self::on_canvas(ctx, x, y);
+            if let Some(button) = screen::hit(self.labels.len(), at) {
+                self.mouse_held = Some(button);
+                self.send(self.mouse_source, button, true);
+            }
+        }
+
+        /// A button held with the mouse stays down until the mouse button is let go, wherever
+        /// the pointer has moved to.
+        fn mouse_button_up_event(
+            &mut self,
+            _ctx: &mut Context,
+            mouse_button: MouseButton,
+            _x: f32,
+            _y: f32,
+        ) {
+            if mouse_button != MouseButton::Left {
+                return;
+            }
+            if let Some(button) = self.mouse_held.take() {
+                self.send(self.mouse_source, button, false);
             }
         }
     }
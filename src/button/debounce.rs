@@ -0,0 +1,111 @@
+//! Contact bounce filtering, shared by the hardware backends.
+
+use std::time::{Duration, SystemTime};
+
+/// The state of a button whose contacts may bounce. Changes of state less than `window`
+/// apart are taken to be bounce, and only the state the contacts settle in counts.
+pub struct Debounce {
+    window: Duration,
+    /// the state the button is considered to be in
+    stable: bool,
+    /// the state the last change put the contacts in, which may still be bouncing
+    raw: bool,
+    /// when `stable` last changed
+    changed_at: SystemTime,
+}
+
+impl Debounce {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            stable: false,
+            raw: false,
+            changed_at: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    /// The contacts were found to be closed or open `at` some time.
+    pub fn update(&mut self, closed: bool, at: SystemTime) {
+        self.stable = self.pressed(at);
+        self.raw = closed;
+        if self.raw != self.stable && self.settled(at) {
+            self.stable = self.raw;
+            self.changed_at = at;
+        }
+    }
+
+    /// Whether the button is down as of `now`. A button that is still bouncing keeps its last
+    /// stable state until the debounce window is over.
+    pub fn pressed(&self, now: SystemTime) -> bool {
+        if self.raw != self.stable && self.settled(now) {
+            self.raw
+        } else {
+            self.stable
+        }
+    }
+
+    /// Let a bounce that ended in a new state take effect, if its window is over by `now`.
+    pub fn settle(&mut self, now: SystemTime) {
+        self.stable = self.pressed(now);
+    }
+
+    /// When a bounce that ended in a new state takes effect, if one is waiting to and hasn't
+    /// been settled yet.
+    pub fn settles_at(&self) -> Option<SystemTime> {
+        (self.raw != self.stable).then(|| self.changed_at + self.window)
+    }
+
+    fn settled(&self, now: SystemTime) -> bool {
+        now.duration_since(self.changed_at)
+            .is_ok_and(|since| since >= self.window)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_millis(20);
+
+    fn at(ms: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_000) + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn bounces_are_ignored() {
+        let mut debounce = Debounce::new(WINDOW);
+        let states: Vec<bool> = [
+            (0, true),
+            (2, false),
+            (4, true),
+            (300, false),
+            (303, true),
+            (305, false),
+        ]
+        .into_iter()
+        .map(|(ms, closed)| {
+            debounce.update(closed, at(ms));
+            debounce.pressed(at(ms))
+        })
+        .collect();
+        assert_eq!(states, [true, true, true, false, false, false]);
+    }
+
+    #[test]
+    fn a_bounce_that_settles_takes_effect_once_the_window_is_over() {
+        let mut debounce = Debounce::new(WINDOW);
+        debounce.update(true, at(0));
+        assert_eq!(debounce.settles_at(), None);
+        // a tap shorter than the window still ends
+        debounce.update(false, at(5));
+        assert_eq!(debounce.settles_at(), Some(at(20)));
+        assert!(debounce.pressed(at(10)));
+        assert!(!debounce.pressed(at(25)));
+        // once settled there is nothing left to wait for
+        debounce.settle(at(10));
+        assert_eq!(debounce.settles_at(), Some(at(20)));
+        debounce.settle(at(25));
+        assert_eq!(debounce.settles_at(), None);
+        assert!(!debounce.pressed(at(30)));
+    }
+}
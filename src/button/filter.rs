@@ -0,0 +1,158 @@
+//! Choosing the devices and keys that act as the button, and a way to find out what a button
+//! sends.
+
+use std::thread;
+
+use evdev::{Device, InputEventKind, Key};
+
+use crate::config::{self, DeviceFilter};
+
+pub fn parse_keys(keys: &[String]) -> anyhow::Result<Vec<Key>> {
+    keys.iter()
+        .map(|key| match key.parse::<u16>() {
+            Ok(code) => Ok(Key::new(code)),
+            Err(_) => key
+                .parse::<Key>()
+                .map_err(|_| anyhow::anyhow!("Unknown key {key:?}, run `ushidashi keys`")),
+        })
+        .collect()
+}
+
+/// What a device says about itself, for matching against [`DeviceFilter`]s.
+pub struct Identity {
+    pub name: String,
+    pub phys: String,
+    pub vendor: u16,
+    pub product: u16,
+}
+
+impl Identity {
+    pub fn of(device: &Device) -> Self {
+        let id = device.input_id();
+        Self {
+            name: device.name().unwrap_or_default().to_owned(),
+            phys: device.physical_path().unwrap_or_default().to_owned(),
+            vendor: id.vendor(),
+            product: id.product(),
+        }
+    }
+
+    /// Whether the device is one to listen on. No filters means every device.
+    pub fn allowed_by(&self, filters: &[DeviceFilter]) -> bool {
+        filters.is_empty() || filters.iter().any(|filter| self.matches(filter))
+    }
+
+    fn matches(&self, filter: &DeviceFilter) -> bool {
+        let contains = |have: &str, want: &Option<String>| {
+            want.as_ref()
+                .is_none_or(|want| have.to_lowercase().contains(&want.to_lowercase()))
+        };
+        contains(&self.name, &filter.name)
+            && contains(&self.phys, &filter.phys)
+            && filter.vendor.is_none_or(|vendor| vendor == self.vendor)
+            && filter.product.is_none_or(|product| product == self.product)
+    }
+}
+
+impl std::fmt::Display for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?} (phys {:?}, vendor 0x{:04x}, product 0x{:04x})",
+            self.name, self.phys, self.vendor, self.product
+        )
+    }
+}
+
+/// List every device with keys, then print each key event until interrupted.
+pub fn print_keys(button: &config::Button) -> anyhow::Result<()> {
+    let buttons = super::parts(button)
+        .into_iter()
+        .map(|part| Ok((part.speaker, parse_keys(&part.keys)?)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let mut watchers = Vec::new();
+    for (path, mut device) in evdev::enumerate() {
+        if !device.supported_events().contains(evdev::EventType::KEY) {
+            continue;
+        }
+        let identity = Identity::of(&device);
+        let used = if identity.allowed_by(&button.devices) {
+            "listened on"
+        } else {
+            "ignored by the device filters"
+        };
+        println!("{}: {identity}, {used}", path.display());
+        let buttons = buttons.clone();
+        watchers.push(thread::spawn(move || loop {
+            let events = match device.fetch_events() {
+                Ok(events) => events,
+                Err(e) => {
+                    println!("{}: {e}", path.display());
+                    return;
+                }
+            };
+            for event in events {
+                let InputEventKind::Key(key) = event.kind() else {
+                    continue;
+                };
+                let action = match event.value() {
+                    0 => "released",
+                    1 => "pressed",
+                    _ => "repeated",
+                };
+                let trigger = match buttons.iter().find(|(_, keys)| keys.contains(&key)) {
+                    Some((Some(speaker), _)) => format!(", {speaker}'s button"),
+                    Some((None, _)) => ", a trigger key".into(),
+                    None => String::new(),
+                };
+                println!(
+                    "{}: {key:?} (code {}) {action}{trigger}",
+                    path.display(),
+                    key.code()
+                );
+            }
+        }));
+    }
+    anyhow::ensure!(
+        !watchers.is_empty(),
+        "No input devices with keys found. Is this user allowed to read /dev/input?"
+    );
+    println!("Press keys to see their codes, Ctrl-C to stop.");
+    for watcher in watchers {
+        let _ = watcher.join();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_by_name_or_number() {
+        let keys = parse_keys(&["KEY_SPACE".into(), "BTN_TRIGGER".into(), "30".into()]).unwrap();
+        assert_eq!(keys, [Key::KEY_SPACE, Key::BTN_TRIGGER, Key::KEY_A]);
+        assert!(parse_keys(&["SPACE".into()]).is_err());
+    }
+
+    #[test]
+    fn filters_match_on_every_field_they_set() {
+        let arcade = Identity {
+            name: "DragonRise Inc.   Generic   USB  Joystick  ".into(),
+            phys: "usb-3f980000.usb-1.3/input0".into(),
+            vendor: 0x0079,
+            product: 0x0006,
+        };
+        let filter = |name: Option<&str>, vendor| DeviceFilter {
+            name: name.map(Into::into),
+            vendor,
+            ..Default::default()
+        };
+
+        assert!(arcade.allowed_by(&[]));
+        assert!(arcade.allowed_by(&[filter(Some("dragonrise"), None)]));
+        assert!(arcade.allowed_by(&[filter(Some("dragonrise"), Some(0x0079))]));
+        assert!(!arcade.allowed_by(&[filter(Some("dragonrise"), Some(0x046d))]));
+        assert!(arcade.allowed_by(&[filter(Some("keyboard"), None), filter(None, Some(0x0079))]));
+    }
+}
@@ -0,0 +1,249 @@
+//! Telling taps, multiple presses and holds apart, whichever backend the button is read from.
+
+use std::time::{Duration, Instant};
+
+use crate::config;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gesture {
+    /// A short press, not followed by another.
+    Tap,
+    /// Two short presses in quick succession.
+    DoublePress,
+    /// Three or more short presses in quick succession, and how many.
+    MultiPress(u32),
+    /// The button has been down longer than a tap, and still is.
+    Hold,
+    /// The same press has gone on to be held for the long hold time.
+    LongHold,
+}
+
+/// Turns samples of the button state into gestures. A button that is down when the
+/// recognizer starts has to be released first, so a stuck button makes no gestures.
+pub struct Recognizer {
+    tap: Duration,
+    gap: Duration,
+    long_hold: Duration,
+    /// whether the button has been seen up
+    primed: bool,
+    /// when the current press began
+    down_since: Option<Instant>,
+    /// gestures already reported for the current press
+    held: bool,
+    long_held: bool,
+    /// taps counted so far, and when the last one ended
+    taps: u32,
+    released_at: Option<Instant>,
+}
+
+impl Recognizer {
+    pub fn new(gestures: &config::Gestures) -> Self {
+        Self {
+            tap: gestures.tap(),
+            gap: gestures.gap(),
+            long_hold: gestures.long_hold(),
+            primed: false,
+            down_since: None,
+            held: false,
+            long_held: false,
+            taps: 0,
+            released_at: None,
+        }
+    }
+
+    /// When the next gesture could be complete without the button changing, if one could.
+    /// The button should be looked at again then, as well as whenever it changes.
+    pub fn deadline(&self) -> Option<Instant> {
+        match self.down_since {
+            Some(since) if !self.held => Some(since + self.tap),
+            Some(since) if !self.long_held => Some(since + self.long_hold),
+            Some(_) => None,
+            None if self.taps > 0 => self.released_at.map(|at| at + self.gap),
+            None => None,
+        }
+    }
+
+    /// Feed the state of the button as of `now`. A gesture is only reported once a sample
+    /// shows it to be complete, so samples are needed whenever the button changes and at each
+    /// [`Self::deadline`].
+    pub fn update(&mut self, pressed: bool, now: Instant) -> Option<Gesture> {
+        match (self.down_since, pressed) {
+            (None, true) => {
+                if self.primed {
+                    self.down_since = Some(now);
+                }
+                None
+            }
+            (Some(since), true) => {
+                let held = now.duration_since(since);
+                if !self.held && held >= self.tap {
+                    // a hold ends any run of taps before it
+                    self.held = true;
+                    self.taps = 0;
+                    return Some(Gesture::Hold);
+                }
+                if self.held && !self.long_held && held >= self.long_hold {
+                    self.long_held = true;
+                    return Some(Gesture::LongHold);
+                }
+                None
+            }
+            (Some(_), false) => {
+                self.down_since = None;
+                if !self.held {
+                    self.taps += 1;
+                    self.released_at = Some(now);
+                }
+                self.held = false;
+                self.long_held = false;
+                None
+            }
+            (None, false) => {
+                self.primed = true;
+                let over = self
+                    .released_at
+                    .is_some_and(|at| now.duration_since(at) >= self.gap);
+                if self.taps == 0 || !over {
+                    return None;
+                }
+                let taps = std::mem::take(&mut self.taps);
+                Some(match taps {
+                    1 => Gesture::Tap,
+                    2 => Gesture::DoublePress,
+                    n => Gesture::MultiPress(n),
+                })
+            }
+        }
+    }
+}
+
+impl config::Gestures {
+    /// What a gesture does when questions are recorded in `mode`.
+    pub fn action(&self, gesture: Gesture, mode: config::RecordMode) -> config::Action {
+        match gesture {
+            Gesture::Tap => self.tap.unwrap_or(match mode {
+                config::RecordMode::PushToTalk => config::Action::Nothing,
+                config::RecordMode::HandsFree => config::Action::Ask,
+            }),
+            Gesture::DoublePress => self.double_press,
+            Gesture::MultiPress(_) => self.multi_press,
+            Gesture::Hold => self.hold,
+            Gesture::LongHold => self.long_hold,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the recognizer over `(pressed, ms)` changes of the button, sampling every
+    /// millisecond, and returns what it reported.
+    fn recognize(changes: &[(bool, u64)], until_ms: u64) -> Vec<Gesture> {
+        let mut recognizer = Recognizer::new(&config::Gestures::default());
+        let start = Instant::now();
+        let mut pressed = false;
+        let mut gestures = Vec::new();
+        for ms in 0..until_ms {
+            if let Some(&(state, _)) = changes.iter().find(|&&(_, at)| at == ms) {
+                pressed = state;
+            }
+            let now = start + Duration::from_millis(ms);
+            gestures.extend(recognizer.update(pressed, now));
+        }
+        gestures
+    }
+
+    #[test]
+    fn taps() {
+        assert_eq!(recognize(&[(true, 10), (false, 100)], 1000), [Gesture::Tap]);
+        assert_eq!(
+            recognize(&[(true, 10), (false, 100), (true, 300), (false, 400)], 1500),
+            [Gesture::DoublePress]
+        );
+        assert_eq!(
+            recognize(
+                &[
+                    (true, 10),
+                    (false, 100),
+                    (true, 300),
+                    (false, 400),
+                    (true, 600),
+                    (false, 700)
+                ],
+                1500
+            ),
+            [Gesture::MultiPress(3)]
+        );
+        // too far apart to be one gesture
+        assert_eq!(
+            recognize(&[(true, 10), (false, 100), (true, 800), (false, 900)], 2000),
+            [Gesture::Tap, Gesture::Tap]
+        );
+    }
+
+    #[test]
+    fn holds() {
+        assert_eq!(
+            recognize(&[(true, 10), (false, 1000)], 2000),
+            [Gesture::Hold]
+        );
+        assert_eq!(
+            recognize(&[(true, 10), (false, 3000)], 4000),
+            [Gesture::Hold, Gesture::LongHold]
+        );
+        // the tap before the hold is dropped
+        assert_eq!(
+            recognize(
+                &[(true, 10), (false, 100), (true, 300), (false, 1000)],
+                2000
+            ),
+            [Gesture::Hold]
+        );
+    }
+
+    #[test]
+    fn deadlines_fall_where_gestures_complete() {
+        let mut recognizer = Recognizer::new(&config::Gestures::default());
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+        recognizer.update(false, ms(0));
+        assert_eq!(recognizer.deadline(), None);
+        recognizer.update(true, ms(10));
+        assert_eq!(recognizer.deadline(), Some(ms(260)));
+        recognizer.update(false, ms(100));
+        assert_eq!(recognizer.deadline(), Some(ms(500)));
+        assert_eq!(recognizer.update(false, ms(500)), Some(Gesture::Tap));
+        assert_eq!(recognizer.deadline(), None);
+    }
+
+    #[test]
+    fn stuck_button() {
+        assert_eq!(recognize(&[(true, 0), (false, 100)], 1000), []);
+        assert_eq!(recognize(&[(true, 0)], 5000), []);
+    }
+
+    #[test]
+    fn a_tap_asks_only_when_recording_hands_free() {
+        use config::{Action, RecordMode};
+
+        let mut gestures = config::Gestures::default();
+        assert_eq!(
+            gestures.action(Gesture::Tap, RecordMode::HandsFree),
+            Action::Ask
+        );
+        assert_eq!(
+            gestures.action(Gesture::Tap, RecordMode::PushToTalk),
+            Action::Nothing
+        );
+        assert_eq!(
+            gestures.action(Gesture::Hold, RecordMode::HandsFree),
+            Action::Ask
+        );
+        gestures.tap = Some(Action::Repeat);
+        assert_eq!(
+            gestures.action(Gesture::Tap, RecordMode::HandsFree),
+            Action::Repeat
+        );
+    }
+}
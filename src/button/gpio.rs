@@ -0,0 +1,212 @@
+//! A button wired to a GPIO line, read through the kernel's GPIO character device.
+
+use std::{
+    thread::{self, JoinHandle},
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use gpio_cdev::{Chip, EventRequestFlags, EventType, LineEventHandle, LineRequestFlags};
+use tokio::sync::mpsc::UnboundedSender;
+
+use super::{new_source, Raw};
+use crate::config::{self, Bias};
+
+/// How the line shows up to other users of the chip, for example in `gpioinfo`.
+const CONSUMER: &str = "ushidashi";
+
+/// Bias flags from the kernel's GPIO uAPI, which gpio-cdev doesn't name. The kernel has taken
+/// them since 5.5 and older kernels refuse the request.
+const BIAS_PULL_UP: u32 = 1 << 5;
+const BIAS_PULL_DOWN: u32 = 1 << 6;
+const BIAS_DISABLE: u32 = 1 << 7;
+
+/// A change of the line, already adjusted for an active low button.
+#[derive(Debug, Clone, Copy)]
+pub struct Edge {
+    pub pressed: bool,
+    pub at: SystemTime,
+}
+
+/// A GPIO line with edge events. Implemented by a line on a real chip, and by mocks in tests.
+pub trait Line: Send + 'static {
+    /// Whether the button is down right now.
+    fn value(&mut self) -> anyhow::Result<bool>;
+    /// Waits for the next edge. `None` means the line is gone.
+    fn next_edge(&mut self) -> anyhow::Result<Option<Edge>>;
+}
+
+/// A line requested from a `/dev/gpiochip*` device.
+pub struct ChipLine {
+    events: LineEventHandle,
+}
+
+impl ChipLine {
+    pub fn open(gpio: &config::Gpio) -> anyhow::Result<Self> {
+        let mut chip =
+            Chip::new(&gpio.chip).with_context(|| format!("can't open {:?}", gpio.chip))?;
+        let line = chip
+            .get_line(gpio.line)
+            .with_context(|| format!("{:?} has no line {}", gpio.chip, gpio.line))?;
+        let mut flags = LineRequestFlags::INPUT.bits();
+        if gpio.active_low {
+            flags |= LineRequestFlags::ACTIVE_LOW.bits();
+        }
+        flags |= match gpio.bias {
+            Bias::AsIs => 0,
+            Bias::PullUp => BIAS_PULL_UP,
+            Bias::PullDown => BIAS_PULL_DOWN,
+            Bias::Disabled => BIAS_DISABLE,
+        };
+        // SAFETY: the bits are all request flags the kernel defines; the type only lacks
+        // names for the bias ones
+        let flags = unsafe { LineRequestFlags::from_bits_unchecked(flags) };
+        let events = line
+            .events(flags, EventRequestFlags::BOTH_EDGES, CONSUMER)
+            .with_context(|| {
+                format!(
+                    "can't request line {} of {:?}, is something else using it?",
+                    gpio.line, gpio.chip
+                )
+            })?;
+        Ok(Self { events })
+    }
+}
+
+impl Line for ChipLine {
+    fn value(&mut self) -> anyhow::Result<bool> {
+        Ok(self.events.get_value()? != 0)
+    }
+
+    fn next_edge(&mut self) -> anyhow::Result<Option<Edge>> {
+        let event = self.events.get_event()?;
+        // the kernel stamps events with the monotonic clock on most versions, which can't be
+        // compared with the time the button is asked about, so the time of reading it is used
+        Ok(Some(Edge {
+            pressed: event.event_type() == EventType::RisingEdge,
+            at: SystemTime::now(),
+        }))
+    }
+}
+
+/// A button on a GPIO line, reporting its edges as they come.
+pub struct GpioButton {
+    thread: JoinHandle<()>,
+}
+
+impl GpioButton {
+    pub fn open(
+        gpio: &config::Gpio,
+        button: usize,
+        raw: UnboundedSender<Raw>,
+    ) -> anyhow::Result<Self> {
+        Self::start(ChipLine::open(gpio)?, button, raw)
+    }
+
+    /// Follow the edges of any line, as the button numbered `button`.
+    pub fn start(
+        mut line: impl Line,
+        button: usize,
+        raw: UnboundedSender<Raw>,
+    ) -> anyhow::Result<Self> {
+        let source = new_source();
+        let mut down = line.value()?;
+        if down {
+            let _ = raw.send(Raw {
+                source,
+                button,
+                down,
+                at: SystemTime::now(),
+            });
+        }
+        let thread = thread::spawn(move || {
+            loop {
+                match line.next_edge() {
+                    Ok(Some(edge)) => {
+                        down = edge.pressed;
+                        let change = Raw {
+                            source,
+                            button,
+                            down,
+                            at: edge.at,
+                        };
+                        if raw.send(change).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        eprintln!("Stopped reading the GPIO button: {e:#}");
+                        break;
+                    }
+                }
+            }
+            // a line that can't be read can't be holding the button down
+            if down {
+                let _ = raw.send(Raw {
+                    source,
+                    button,
+                    down: false,
+                    at: SystemTime::now(),
+                });
+            }
+        });
+        Ok(Self { thread })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{channel, Receiver, Sender};
+
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+    use super::*;
+
+    /// A line whose edges are sent by the test.
+    struct MockLine {
+        initial: bool,
+        edges: Receiver<bool>,
+    }
+
+    impl Line for MockLine {
+        fn value(&mut self) -> anyhow::Result<bool> {
+            Ok(self.initial)
+        }
+
+        fn next_edge(&mut self) -> anyhow::Result<Option<Edge>> {
+            Ok(self.edges.recv().ok().map(|pressed| Edge {
+                pressed,
+                at: SystemTime::now(),
+            }))
+        }
+    }
+
+    fn mock(initial: bool) -> (Sender<bool>, UnboundedReceiver<Raw>) {
+        let (tx, edges) = channel();
+        let (raw, changes) = unbounded_channel();
+        GpioButton::start(MockLine { initial, edges }, 3, raw).unwrap();
+        (tx, changes)
+    }
+
+    #[test]
+    fn follows_edges() {
+        let (tx, mut changes) = mock(false);
+        tx.send(true).unwrap();
+        let change = changes.blocking_recv().unwrap();
+        assert_eq!((change.button, change.down), (3, true));
+        tx.send(false).unwrap();
+        assert!(!changes.blocking_recv().unwrap().down);
+        drop(tx);
+        assert!(changes.blocking_recv().is_none());
+    }
+
+    #[test]
+    fn starts_held_and_lets_go_when_the_line_goes() {
+        let (tx, mut changes) = mock(true);
+        assert!(changes.blocking_recv().unwrap().down);
+        drop(tx);
+        assert!(!changes.blocking_recv().unwrap().down);
+        assert!(changes.blocking_recv().is_none());
+    }
+}
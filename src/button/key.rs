@@ -0,0 +1,106 @@
+//! Turning a device's raw key events into the state of the button. This lives outside the
+//! evdev backend so it can be tested whichever backend is built.
+
+use evdev::{InputEvent, InputEventKind, Key};
+
+/// Key event values, as defined by the kernel.
+const RELEASE: i32 = 0;
+const PRESS: i32 = 1;
+
+/// Follows the trigger keys through the events of one device. The button is down while any
+/// of them is.
+pub struct KeyTracker {
+    keys: Vec<Key>,
+    /// trigger keys the events say are down
+    down: Vec<Key>,
+}
+
+impl KeyTracker {
+    pub fn new(keys: Vec<Key>) -> Self {
+        Self {
+            keys,
+            down: Vec::new(),
+        }
+    }
+
+    /// Follow one event. Returns the new state of the button if the event changed it.
+    pub fn handle(&mut self, event: &InputEvent) -> Option<bool> {
+        let InputEventKind::Key(key) = event.kind() else {
+            return None;
+        };
+        if !self.keys.contains(&key) {
+            return None;
+        }
+        let before = self.pressed();
+        match event.value() {
+            PRESS if !self.down.contains(&key) => self.down.push(key),
+            RELEASE => self.down.retain(|&k| k != key),
+            // auto-repeat, which says nothing new about the key
+            _ => return None,
+        }
+        (self.pressed() != before).then(|| self.pressed())
+    }
+
+    /// Whether the button is down, before debouncing.
+    pub fn pressed(&self) -> bool {
+        !self.down.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use evdev::EventType;
+
+    use super::*;
+
+    const REPEAT: i32 = 2;
+
+    /// Feed `(key, value)` events and return the state after each.
+    fn feed(events: &[(Key, i32)]) -> Vec<bool> {
+        let mut tracker = KeyTracker::new(vec![Key::KEY_SPACE, Key::BTN_TRIGGER]);
+        events
+            .iter()
+            .map(|&(key, value)| {
+                tracker.handle(&InputEvent::new(EventType::KEY, key.code(), value));
+                tracker.pressed()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn press_and_release_are_followed_and_repeats_ignored() {
+        let space = Key::KEY_SPACE;
+        let states = feed(&[
+            (space, PRESS),
+            (space, REPEAT),
+            (space, REPEAT),
+            (space, RELEASE),
+            (Key::KEY_A, PRESS),
+            (space, PRESS),
+        ]);
+        assert_eq!(states, [true, true, true, false, false, true]);
+    }
+
+    #[test]
+    fn held_while_any_trigger_key_is() {
+        let states = feed(&[
+            (Key::KEY_SPACE, PRESS),
+            (Key::BTN_TRIGGER, PRESS),
+            (Key::KEY_SPACE, RELEASE),
+            (Key::BTN_TRIGGER, RELEASE),
+        ]);
+        assert_eq!(states, [true, true, true, false]);
+    }
+
+    #[test]
+    fn only_changes_are_reported() {
+        let mut tracker = KeyTracker::new(vec![Key::KEY_SPACE, Key::BTN_TRIGGER]);
+        let mut handle =
+            |key: Key, value| tracker.handle(&InputEvent::new(EventType::KEY, key.code(), value));
+        assert_eq!(handle(Key::KEY_SPACE, PRESS), Some(true));
+        assert_eq!(handle(Key::KEY_SPACE, REPEAT), None);
+        assert_eq!(handle(Key::BTN_TRIGGER, PRESS), None);
+        assert_eq!(handle(Key::KEY_SPACE, RELEASE), None);
+        assert_eq!(handle(Key::BTN_TRIGGER, RELEASE), Some(false));
+    }
+}
@@ -0,0 +1,383 @@
+//! Drawing the emulator window: a big button for each speaker that shows what the toy is
+//! doing, and captions of the last question and answer. The picture is drawn in memory and
+//! handed to the GPU whole, which keeps the drawing simple enough to test.
+
+use std::convert::Infallible;
+
+use embedded_graphics::{
+    mono_font::{
+        ascii::{FONT_10X20, FONT_9X15},
+        MonoFont, MonoTextStyle,
+    },
+    pixelcolor::Rgb888,
+    prelude::*,
+    primitives::{Circle, PrimitiveStyle},
+    text::{Alignment, Text},
+};
+use miniquad::{
+    Bindings, Buffer, BufferLayout, BufferType, Context, Pipeline, Shader, ShaderMeta, Texture,
+    UniformBlockLayout, VertexAttribute, VertexFormat,
+};
+
+use crate::indicator::{Captions, State};
+
+pub const WIDTH: u32 = 800;
+pub const HEIGHT: u32 = 600;
+
+const BACKGROUND: Rgb888 = Rgb888::new(24, 24, 32);
+const TEXT: Rgb888 = Rgb888::new(230, 230, 230);
+const DIM_TEXT: Rgb888 = Rgb888::new(150, 150, 160);
+/// how far a held button sinks into its rim
+const TRAVEL: i32 = 10;
+const CAPTIONS_TOP: i32 = 440;
+const QUESTION_LINES: usize = 2;
+const ANSWER_LINES: usize = 6;
+
+/// Everything that is drawn.
+#[derive(Clone, PartialEq)]
+pub struct Scene {
+    pub state: State,
+    /// each button's label, and whether it is held down
+    pub buttons: Vec<(String, bool)>,
+    pub captions: Captions,
+}
+
+/// An RGBA picture the size of the window.
+pub struct Canvas {
+    pub pixels: Vec<u8>,
+}
+
+impl Canvas {
+    pub fn new() -> Self {
+        Self {
+            pixels: vec![0; (WIDTH * HEIGHT * 4) as usize],
+        }
+    }
+
+    fn pixel(&self, at: Point) -> Rgb888 {
+        let i = (at.y as usize * WIDTH as usize + at.x as usize) * 4;
+        Rgb888::new(self.pixels[i], self.pixels[i + 1], self.pixels[i + 2])
+    }
+}
+
+impl OriginDimensions for Canvas {
+    fn size(&self) -> Size {
+        Size::new(WIDTH, HEIGHT)
+    }
+}
+
+impl DrawTarget for Canvas {
+    type Color = Rgb888;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Infallible>
+    where
+        I: IntoIterator<Item = Pixel<Rgb888>>,
+    {
+        for Pixel(at, color) in pixels {
+            if at.x < 0 || at.y < 0 || at.x >= WIDTH as i32 || at.y >= HEIGHT as i32 {
+                continue;
+            }
+            let i = (at.y as usize * WIDTH as usize + at.x as usize) * 4;
+            self.pixels[i..i + 4].copy_from_slice(&[color.r(), color.g(), color.b(), 0xff]);
+        }
+        Ok(())
+    }
+}
+
+/// The center and radius of each button.
+fn layout(buttons: usize) -> Vec<(Point, u32)> {
+    let slot = WIDTH / buttons.max(1) as u32;
+    let radius = (slot / 2).saturating_sub(20).min(150);
+    (0..buttons)
+        .map(|i| {
+            let x = slot * i as u32 + slot / 2;
+            (Point::new(x as i32, 230), radius)
+        })
+        .collect()
+}
+
+/// Which button a point in the window is on, if any.
+pub fn hit(buttons: usize, at: Point) -> Option<usize> {
+    layout(buttons).into_iter().position(|(center, radius)| {
+        let offset = at - center;
+        offset.x.pow(2) + offset.y.pow(2) <= (radius as i32).pow(2)
+    })
+}
+
+/// The button is red while the toy waits for a question, and takes on a color of its own for
+/// each step of answering one.
+fn color(state: State) -> Rgb888 {
+    match state {
+        State::Idle => Rgb888::new(200, 24, 24),
+        State::Recording => Rgb888::new(255, 72, 48),
+        State::Transcribing => Rgb888::new(232, 120, 24),
+        State::Thinking => Rgb888::new(224, 184, 32),
+        State::Speaking => Rgb888::new(40, 168, 72),
+        State::Error => Rgb888::new(120, 40, 160),
+    }
+}
+
+fn status(state: State) -> &'static str {
+    match state {
+        State::Idle => "hold the button and ask something",
+        State::Recording => "listening...",
+        State::Transcribing => "working out what was said...",
+        State::Thinking => "thinking...",
+        State::Speaking => "answering...",
+        State::Error => "something went wrong, try again",
+    }
+}
+
+fn shade(color: Rgb888, factor: f32) -> Rgb888 {
+    let scale = |c: u8| (c as f32 * factor) as u8;
+    Rgb888::new(scale(color.r()), scale(color.g()), scale(color.b()))
+}
+
+pub fn render(scene: &Scene, canvas: &mut Canvas) {
+    let _ = draw(scene, canvas);
+}
+
+fn draw(scene: &Scene, canvas: &mut Canvas) -> Result<(), Infallible> {
+    canvas.clear(BACKGROUND)?;
+    let large = MonoTextStyle::new(&FONT_10X20, TEXT);
+    Text::with_alignment(
+        status(scene.state),
+        Point::new(WIDTH as i32 / 2, 40),
+        large,
+        Alignment::Center,
+    )
+    .draw(canvas)?;
+
+    let face = color(scene.state);
+    for ((center, radius), (label, down)) in
+        layout(scene.buttons.len()).into_iter().zip(&scene.buttons)
+    {
+        let diameter = radius * 2;
+        Circle::with_center(center + Point::new(0, TRAVEL), diameter)
+            .into_styled(PrimitiveStyle::with_fill(shade(face, 0.45)))
+            .draw(canvas)?;
+        let (cap, sink) = if *down {
+            (shade(face, 0.8), TRAVEL)
+        } else {
+            (face, 0)
+        };
+        Circle::with_center(center + Point::new(0, sink), diameter)
+            .into_styled(PrimitiveStyle::with_fill(cap))
+            .draw(canvas)?;
+        let below = center + Point::new(0, radius as i32 + TRAVEL + 30);
+        Text::with_alignment(label, below, large, Alignment::Center).draw(canvas)?;
+    }
+
+    let captions = &scene.captions;
+    let mut y = CAPTIONS_TOP;
+    if !captions.question.is_empty() {
+        let asker = captions.speaker.as_deref().unwrap_or("you");
+        let question = format!("{asker}: {}", plain(&captions.question));
+        y = caption(canvas, &question, DIM_TEXT, QUESTION_LINES, y)?;
+    }
+    if !captions.answer.is_empty() {
+        let answer = format!("ushidashi: {}", plain(&captions.answer));
+        caption(canvas, &answer, TEXT, ANSWER_LINES, y + 8)?;
+    }
+    Ok(())
+}
+
+/// Draw wrapped text from `y` down, returning where the next line would go.
+fn caption(
+    canvas: &mut Canvas,
+    text: &str,
+    color: Rgb888,
+    max_lines: usize,
+    mut y: i32,
+) -> Result<i32, Infallible> {
+    let font: &MonoFont = &FONT_9X15;
+    let style = MonoTextStyle::new(font, color);
+    let columns = (WIDTH as usize - 40) / font.character_size.width as usize;
+    for line in wrap(text, columns, max_lines) {
+        y += font.character_size.height as i32 + 2;
+        Text::new(&line, Point::new(20, y), style).draw(canvas)?;
+    }
+    Ok(y)
+}
+
+/// The text of an answer without the SSML tags it may be marked up with.
+fn plain(text: &str) -> String {
+    let mut plain = String::new();
+    let mut in_tag = false;
+    for c in text.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag => plain.push(c),
+            _ => {}
+        }
+    }
+    plain.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Break text into lines of at most `columns` characters, between words where possible. Text
+/// that doesn't fit in `max_lines` is cut short with an ellipsis.
+fn wrap(text: &str, columns: usize, max_lines: usize) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        let mut word: Vec<char> = word.chars().collect();
+        loop {
+            let taken = line.chars().count();
+            let gap = usize::from(taken > 0);
+            if taken + gap + word.len() <= columns {
+                if gap == 1 {
+                    line.push(' ');
+                }
+                line.extend(word);
+                break;
+            }
+            if taken > 0 {
+                lines.push(std::mem::take(&mut line));
+                continue;
+            }
+            // a word longer than a whole line
+            line = word.drain(..columns).collect();
+        }
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    if lines.len() > max_lines {
+        lines.truncate(max_lines);
+        let last = &mut lines[max_lines - 1];
+        let keep = last.chars().count().min(columns.saturating_sub(3));
+        *last = last.chars().take(keep).collect::<String>() + "...";
+    }
+    lines
+}
+
+/// Shows a [`Canvas`] in the window, stretched over all of it.
+pub struct Gpu {
+    pipeline: Pipeline,
+    bindings: Bindings,
+}
+
+impl Gpu {
+    pub fn new(ctx: &mut Context) -> Self {
+        // corners of the window, with the top of the picture at the top
+        #[rustfmt::skip]
+        let vertices: [f32; 16] = [
+            -1.0, -1.0, 0.0, 1.0,
+             1.0, -1.0, 1.0, 1.0,
+             1.0,  1.0, 1.0, 0.0,
+            -1.0,  1.0, 0.0, 0.0,
+        ];
+        let vertex_buffer = Buffer::immutable(ctx, BufferType::VertexBuffer, &vertices);
+        let indices: [u16; 6] = [0, 1, 2, 0, 2, 3];
+        let index_buffer = Buffer::immutable(ctx, BufferType::IndexBuffer, &indices);
+        let texture = Texture::from_rgba8(ctx, WIDTH as u16, HEIGHT as u16, &Canvas::new().pixels);
+        let bindings = Bindings {
+            vertex_buffers: vec![vertex_buffer],
+            index_buffer,
+            images: vec![texture],
+        };
+        let meta = ShaderMeta {
+            images: vec!["tex".to_owned()],
+            uniforms: UniformBlockLayout { uniforms: vec![] },
+        };
+        let shader = Shader::new(ctx, VERTEX, FRAGMENT, meta).expect("the shader compiles");
+        let pipeline = Pipeline::new(
+            ctx,
+            &[BufferLayout::default()],
+            &[
+                VertexAttribute::new("pos", VertexFormat::Float2),
+                VertexAttribute::new("uv", VertexFormat::Float2),
+            ],
+            shader,
+        );
+        Self { pipeline, bindings }
+    }
+
+    /// Replace the picture shown with `canvas`.
+    pub fn upload(&self, ctx: &mut Context, canvas: &Canvas) {
+        self.bindings.images[0].update(ctx, &canvas.pixels);
+    }
+
+    pub fn draw(&self, ctx: &mut Context) {
+        ctx.begin_default_pass(Default::default());
+        ctx.apply_pipeline(&self.pipeline);
+        ctx.apply_bindings(&self.bindings);
+        ctx.draw(0, 6, 1);
+        ctx.end_render_pass();
+        ctx.commit_frame();
+    }
+}
+
+const VERTEX: &str = r#"#version 100
+attribute vec2 pos;
+attribute vec2 uv;
+
+varying lowp vec2 texcoord;
+
+void main() {
+    gl_Position = vec4(pos, 0, 1);
+    texcoord = uv;
+}"#;
+
+const FRAGMENT: &str = r#"#version 100
+varying lowp vec2 texcoord;
+
+uniform sampler2D tex;
+
+void main() {
+    gl_FragColor = texture2D(tex, texcoord);
+}"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scene(state: State, down: bool) -> Scene {
+        Scene {
+            state,
+            buttons: vec![("space".into(), down)],
+            captions: Captions::default(),
+        }
+    }
+
+    #[test]
+    fn the_button_shows_the_state_and_sinks_when_held() {
+        let mut canvas = Canvas::new();
+        let (center, radius) = layout(1)[0];
+        let top = center - Point::new(0, radius as i32 - 2);
+
+        render(&scene(State::Idle, false), &mut canvas);
+        assert_eq!(canvas.pixel(center), color(State::Idle));
+        assert_eq!(canvas.pixel(top), color(State::Idle));
+
+        render(&scene(State::Thinking, false), &mut canvas);
+        assert_eq!(canvas.pixel(center), color(State::Thinking));
+
+        // held down, the top of the cap has moved below where it was
+        render(&scene(State::Recording, true), &mut canvas);
+        assert_ne!(canvas.pixel(top), shade(color(State::Recording), 0.8));
+        assert_eq!(canvas.pixel(center), shade(color(State::Recording), 0.8));
+    }
+
+    #[test]
+    fn clicks_land_on_the_button_under_them() {
+        assert_eq!(hit(1, Point::new(400, 230)), Some(0));
+        assert_eq!(hit(1, Point::new(400, 20)), None);
+        assert_eq!(hit(2, Point::new(200, 230)), Some(0));
+        assert_eq!(hit(2, Point::new(600, 230)), Some(1));
+    }
+
+    #[test]
+    fn captions_are_plain_and_wrapped() {
+        assert_eq!(
+            plain("<speak>Hello  <break time=\"1s\"/>there</speak>"),
+            "Hello there"
+        );
+        assert_eq!(wrap("one two three", 7, 3), ["one two", "three"]);
+        assert_eq!(wrap("abcdefghij", 4, 3), ["abcd", "efgh", "ij"]);
+        assert_eq!(wrap("one two three four", 7, 2), ["one two", "thre..."]);
+        assert!(wrap("", 10, 2).is_empty());
+    }
+}
@@ -6,6 +6,7 @@ use std::fs::{create_dir_all, File, OpenOptions};
 use std::io::{BufRead, BufReader, Write};
 use std::path::PathBuf;
 
+use crate::audio::InputStats;
 use crate::consts::PROJECT_NAME;
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -18,6 +19,25 @@ pub enum Author {
 pub struct LogMessage {
     pub author: Author,
     pub text: String,
+    /// Set when the listener stopped a bot reply before it finished playing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cut_off: Option<CutOff>,
+    /// Names the archived recording a user message was transcribed from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio_id: Option<String>,
+    /// Levels of the recording a user message was transcribed from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input: Option<InputStats>,
+    /// Who asked, when they pressed a button of their own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speaker: Option<String>,
+}
+
+/// How much of a spoken reply was heard before it was interrupted.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct CutOff {
+    pub played_ms: u64,
+    pub total_ms: u64,
 }
 
 impl LogMessage {
@@ -25,6 +45,10 @@ impl LogMessage {
         Self {
             author: Author::Bot,
             text: text.into(),
+            cut_off: None,
+            audio_id: None,
+            input: None,
+            speaker: None,
         }
     }
 
@@ -32,17 +56,39 @@ impl LogMessage {
         Self {
             author: Author::User,
             text: text.into(),
+            cut_off: None,
+            audio_id: None,
+            input: None,
+            speaker: None,
         }
     }
 }
 
 pub fn logfile() -> anyhow::Result<PathBuf> {
+    Ok(data_dir()?.join("convo.jsonl"))
+}
+
+/// Where the chat log and other state that isn't configuration lives.
+pub fn data_dir() -> anyhow::Result<PathBuf> {
     let dir = ProjectDirs::from("", "bddap", PROJECT_NAME).ok_or(anyhow::anyhow!(
         "Could not find the config directory for the application."
     ))?;
     let dir = dir.data_dir();
     create_dir_all(dir).context("Could not create the config directory.")?;
-    Ok(dir.join("convo.jsonl"))
+    Ok(dir.to_path_buf())
+}
+
+/// Set the chat log aside as `convo-<time>.jsonl`, so the next question starts a new
+/// conversation. Returns where it went, if there was one.
+pub fn new_conversation() -> anyhow::Result<Option<PathBuf>> {
+    let current = logfile()?;
+    if !current.exists() {
+        return Ok(None);
+    }
+    let name = chrono::Local::now().format("convo-%Y%m%d-%H%M%S.jsonl");
+    let old = data_dir()?.join(name.to_string());
+    std::fs::rename(&current, &old).context("Could not set the message log aside.")?;
+    Ok(Some(old))
 }
 
 pub fn store_message(message: LogMessage) -> anyhow::Result<()> {
@@ -54,7 +100,11 @@ pub fn store_message(message: LogMessage) -> anyhow::Result<()> {
         .context("Could not open the message log file.")?;
 
     match message.author {
-        Author::User => eprintln!("{}: {}", "user".green(), message.text),
+        Author::User => eprintln!(
+            "{}: {}",
+            message.speaker.as_deref().unwrap_or("user").green(),
+            message.text
+        ),
         Author::Bot => eprintln!("{}: {}", "ushidashi".blue(), message.text),
     }
 
@@ -64,6 +114,37 @@ pub fn store_message(message: LogMessage) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Note that the last message, a bot reply, was interrupted before it finished playing.
+pub fn cut_off_last(cut_off: CutOff) -> anyhow::Result<()> {
+    let mut messages = load_messages()?;
+    let Some(
+        last @ LogMessage {
+            author: Author::Bot,
+            ..
+        },
+    ) = messages.last_mut()
+    else {
+        anyhow::bail!("The last message in the log is not a reply to cut off.");
+    };
+    last.cut_off = Some(cut_off);
+    eprintln!(
+        "{}",
+        format!("(cut off at {}/{} ms)", cut_off.played_ms, cut_off.total_ms).yellow()
+    );
+
+    let mut log = String::new();
+    for message in &messages {
+        log += &serde_json::to_string(message)?;
+        log.push('\n');
+    }
+    // written aside and moved into place, so a crash can't leave half a log
+    let message_path = logfile()?;
+    let partial = message_path.with_extension("jsonl.partial");
+    std::fs::write(&partial, log).context("Could not write the message log file.")?;
+    std::fs::rename(&partial, &message_path).context("Could not replace the message log file.")?;
+    Ok(())
+}
+
 pub fn load_messages() -> anyhow::Result<Vec<LogMessage>> {
     let message_path = logfile()?;
     let message_file = File::open(message_path);
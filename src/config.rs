@@ -1,3 +1,6 @@
+use std::{path::PathBuf, str::FromStr, time::Duration};
+
+use anyhow::Context;
 use serde::{Deserialize, Serialize};
 use xdg::BaseDirectories;
 
@@ -23,3 +26,613 @@ impl Secrets {
         Ok(ret)
     }
 }
+
+/// Non-secret settings, read from config.toml next to secrets.toml. The file is optional and
+/// any key left out takes its default value.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Config {
+    pub audio: Audio,
+    pub button: Button,
+    pub recording: Recording,
+    pub upload: Upload,
+    pub tts: Tts,
+    pub wake_word: WakeWord,
+    pub archive: Archive,
+    pub gestures: Gestures,
+    pub indicator: Indicator,
+}
+
+impl Config {
+    pub fn load() -> anyhow::Result<Self> {
+        let base = BaseDirectories::with_prefix(PROJECT_NAME)?;
+        let Some(path) = base.find_config_file("config.toml") else {
+            return Ok(Self::default());
+        };
+        let config = std::fs::read_to_string(&path)?;
+        let config: Self =
+            toml::from_str(&config).with_context(|| format!("Failed to parse {path:?}"))?;
+        config
+            .upload
+            .check()
+            .with_context(|| format!("Bad [upload] settings in {path:?}"))?;
+        config
+            .indicator
+            .patterns
+            .check()
+            .with_context(|| format!("Bad [indicator.patterns] settings in {path:?}"))?;
+        Ok(config)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Audio {
+    pub source: Source,
+    pub sink: Sink,
+    /// Sound devices are chosen by their exact name or a unique part of it. Run
+    /// `ushidashi devices` to see what names are available. When unset the host's default
+    /// device is used.
+    pub input_device: Option<String>,
+    pub output_device: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct Button {
+    /// Where the button is read from. The emulator ignores this and always uses its window.
+    pub backend: ButtonBackend,
+    /// Keys that act as the button, by name like `KEY_SPACE` or `BTN_TRIGGER`, or by number.
+    /// Run `ushidashi keys` to see what a button sends.
+    pub keys: Vec<String>,
+    /// Input devices to listen on. When empty, every device with keys is used.
+    pub devices: Vec<DeviceFilter>,
+    /// Key changes closer together than this are taken to be contact bounce, and only the
+    /// state the key settles in counts.
+    pub debounce_ms: u64,
+    /// The line used by the `gpio` backend.
+    pub gpio: Gpio,
+    /// Children with a button each, so the toy knows who is asking. When any are listed only
+    /// their buttons are used, and `keys` and `gpio.line` are ignored.
+    pub speakers: Vec<Speaker>,
+}
+
+impl Default for Button {
+    fn default() -> Self {
+        Self {
+            backend: ButtonBackend::default(),
+            keys: vec!["KEY_SPACE".into()],
+            devices: Vec::new(),
+            debounce_ms: 30,
+            gpio: Gpio::default(),
+            speakers: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ButtonBackend {
+    /// Keys on keyboards, gamepads and the like.
+    #[default]
+    Evdev,
+    /// A button wired to a GPIO line, as on a Raspberry Pi.
+    Gpio,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Gpio {
+    pub chip: PathBuf,
+    /// The line offset on the chip. On a Raspberry Pi this is the BCM pin number.
+    pub line: u32,
+    /// Whether the button pulls the line low when pressed, which is how a button between the
+    /// pin and ground with a pull-up is wired.
+    pub active_low: bool,
+    pub bias: Bias,
+}
+
+impl Default for Gpio {
+    fn default() -> Self {
+        Self {
+            chip: "/dev/gpiochip0".into(),
+            line: 17,
+            active_low: true,
+            bias: Bias::PullUp,
+        }
+    }
+}
+
+/// The pull resistor on a GPIO line.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Bias {
+    /// Leave it however the chip or device tree set it.
+    AsIs,
+    PullUp,
+    PullDown,
+    /// No pull, for a line with an external resistor.
+    Disabled,
+}
+
+/// Someone with a button of their own. In the emulator the speakers' buttons are the number
+/// keys, 1 for the first speaker listed and so on.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Speaker {
+    /// How the speaker is named to the chat model.
+    pub name: String,
+    /// Keys for the `evdev` backend.
+    #[serde(default)]
+    pub keys: Vec<String>,
+    /// The line on `gpio.chip` for the `gpio` backend.
+    pub line: Option<u32>,
+}
+
+/// Picks out input devices. A device matches when it matches every field that is set.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct DeviceFilter {
+    /// Part of the device name, ignoring case.
+    pub name: Option<String>,
+    /// Part of the physical path, which says which port the device is plugged into.
+    pub phys: Option<String>,
+    /// USB vendor id, for example `0x0079`.
+    pub vendor: Option<u16>,
+    pub product: Option<u16>,
+}
+
+/// Where recordings come from: `device` for the input device, or `file:<path>` to use a WAV
+/// file in place of whatever is said.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum Source {
+    #[default]
+    Device,
+    File(PathBuf),
+}
+
+/// Where sound goes: `device` for the output device, `file:<dir>` to write each sound to a
+/// numbered WAV file in a directory, or `null` to drop it.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum Sink {
+    #[default]
+    Device,
+    File(PathBuf),
+    Null,
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match (s, s.strip_prefix("file:")) {
+            ("device", _) => Ok(Source::Device),
+            (_, Some(path)) => Ok(Source::File(path.into())),
+            _ => Err(format!(
+                "unknown audio source {s:?}, expected \"device\" or \"file:<path>\""
+            )),
+        }
+    }
+}
+
+impl FromStr for Sink {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match (s, s.strip_prefix("file:")) {
+            ("device", _) => Ok(Sink::Device),
+            ("null", _) => Ok(Sink::Null),
+            (_, Some(dir)) => Ok(Sink::File(dir.into())),
+            _ => Err(format!(
+                "unknown audio sink {s:?}, expected \"device\", \"null\" or \"file:<dir>\""
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for Source {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl TryFrom<String> for Sink {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Source> for String {
+    fn from(source: Source) -> String {
+        match source {
+            Source::Device => "device".into(),
+            Source::File(path) => format!("file:{}", path.display()),
+        }
+    }
+}
+
+impl From<Sink> for String {
+    fn from(sink: Sink) -> String {
+        match sink {
+            Sink::Device => "device".into(),
+            Sink::File(dir) => format!("file:{}", dir.display()),
+            Sink::Null => "null".into(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordMode {
+    /// Record for as long as the button is held.
+    PushToTalk,
+    /// A press starts recording and voice activity detection decides when the question is over.
+    HandsFree,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct Recording {
+    pub mode: RecordMode,
+    /// How much audio from just before the press to keep at the start of each push-to-talk
+    /// recording.
+    pub preroll_ms: u64,
+    /// Recordings shorter than this, not counting pre-roll, are taken to be accidental taps
+    /// and dropped.
+    pub min_duration_ms: u64,
+    /// Recordings are cut off after this long, whether or not the button was released or the
+    /// speaker stopped. This keeps a stuck button from recording forever.
+    pub max_duration_ms: u64,
+    /// Recordings whose loudest 50 ms stay below this level (dBFS RMS) are taken to be
+    /// silence and dropped.
+    pub min_level_db: f32,
+    pub vad: Vad,
+}
+
+impl Default for Recording {
+    fn default() -> Self {
+        Self {
+            mode: RecordMode::PushToTalk,
+            preroll_ms: 300,
+            min_duration_ms: 250,
+            max_duration_ms: 30_000,
+            min_level_db: -45.0,
+            vad: Vad::default(),
+        }
+    }
+}
+
+impl Recording {
+    pub fn preroll(&self) -> Duration {
+        Duration::from_millis(self.preroll_ms)
+    }
+
+    pub fn min_duration(&self) -> Duration {
+        Duration::from_millis(self.min_duration_ms)
+    }
+
+    pub fn max_duration(&self) -> Duration {
+        Duration::from_millis(self.max_duration_ms)
+    }
+}
+
+/// Voice activity detector tuning.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Vad {
+    /// Frames louder than this (dBFS RMS) count as speech.
+    pub threshold_db: f32,
+    /// Frames up to 10 dB quieter than `threshold_db` still count as speech when their
+    /// zero-crossing rate (crossings per sample) is at least this high. This keeps unvoiced
+    /// consonants like "s" and "f" from being mistaken for silence.
+    pub zero_crossing_rate: f32,
+    /// How much silence after speech ends the recording.
+    pub trailing_silence_ms: u64,
+    /// Give up if nobody starts speaking within this long of the press.
+    pub initial_silence_ms: u64,
+}
+
+impl Default for Vad {
+    fn default() -> Self {
+        Self {
+            threshold_db: -40.0,
+            zero_crossing_rate: 0.25,
+            trailing_silence_ms: 1200,
+            initial_silence_ms: 5000,
+        }
+    }
+}
+
+/// Format recordings are converted to before being sent for transcription.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct Upload {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub format: UploadFormat,
+}
+
+impl Upload {
+    /// Whether the upload format can hold recordings converted to these settings.
+    fn check(&self) -> anyhow::Result<()> {
+        let max_channels = match self.format {
+            UploadFormat::Wav | UploadFormat::Flac => 8,
+            UploadFormat::OggOpus => 2,
+        };
+        anyhow::ensure!(
+            (1..=max_channels).contains(&self.channels),
+            "channels must be from 1 to {max_channels} for {:?}, not {}",
+            self.format,
+            self.channels
+        );
+        match self.format {
+            UploadFormat::Wav | UploadFormat::Flac => anyhow::ensure!(
+                (1..1 << 20).contains(&self.sample_rate),
+                "sample_rate must be from 1 to {}, not {}",
+                (1 << 20) - 1,
+                self.sample_rate
+            ),
+            UploadFormat::OggOpus => anyhow::ensure!(
+                [8_000, 12_000, 16_000, 24_000, 48_000].contains(&self.sample_rate),
+                "sample_rate must be 8000, 12000, 16000, 24000 or 48000 for Opus, not {}",
+                self.sample_rate
+            ),
+        }
+        Ok(())
+    }
+}
+
+impl Default for Upload {
+    fn default() -> Self {
+        // Whisper works on 16 kHz mono internally, anything more is wasted bandwidth
+        Self {
+            sample_rate: 16_000,
+            channels: 1,
+            format: UploadFormat::Flac,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadFormat {
+    Wav,
+    /// Lossless, and usually around half the size of WAV for speech.
+    Flac,
+    /// Lossy, but several times smaller than FLAC and transcribed just as well. Needs a
+    /// sample rate of 8, 12, 16, 24 or 48 kHz and at most two channels.
+    OggOpus,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Tts {
+    pub encoding: TtsEncoding,
+}
+
+/// Format spoken answers are downloaded in.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TtsEncoding {
+    /// Uncompressed WAV, around ten times the size of MP3.
+    Linear16,
+    #[default]
+    Mp3,
+    /// Smaller than MP3 for the same quality.
+    OggOpus,
+}
+
+impl TtsEncoding {
+    /// The sample rate speech is asked for in, which the output device is opened at when it
+    /// can be, so answers play without resampling.
+    pub fn sample_rate(self) -> u32 {
+        match self {
+            TtsEncoding::Linear16 | TtsEncoding::Mp3 => 24_000,
+            // Opus in Ogg always decodes at 48 kHz
+            TtsEncoding::OggOpus => 48_000,
+        }
+    }
+}
+
+/// Starting a question by saying "Hey Ushidashi" instead of pressing the button. The spotter
+/// runs entirely on this machine; nothing that is heard leaves it until the wake word is.
+///
+/// The spotter compares what it hears against example recordings of the wake word, WAV files
+/// in `$XDG_CONFIG_HOME/ushidashi/wake_word/`. Make them with `ushidashi enroll`, at least
+/// two and ideally a handful, said the way the toy will usually hear it.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct WakeWord {
+    pub enabled: bool,
+    /// From 0 to 1. Higher values trigger on sloppier matches, at the cost of more false
+    /// alarms.
+    pub sensitivity: f32,
+}
+
+impl Default for WakeWord {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            sensitivity: 0.5,
+        }
+    }
+}
+
+/// Copies of each recording sent for transcription, kept in `recordings/` next to the chat
+/// log. The user message in the log names its recording with `audio_id`.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct Archive {
+    /// Off by default, so nothing that was said is kept once it has been transcribed unless
+    /// someone asks for it.
+    pub enabled: bool,
+    /// The oldest recordings are deleted once all of them together pass this size.
+    pub max_size_mb: u64,
+    /// Recordings older than this are deleted.
+    pub max_age_days: u64,
+}
+
+impl Default for Archive {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_size_mb: 200,
+            max_age_days: 30,
+        }
+    }
+}
+
+impl Archive {
+    pub fn max_age(&self) -> Duration {
+        Duration::from_secs(self.max_age_days * 24 * 60 * 60)
+    }
+}
+
+/// How presses of the button are told apart, and what each kind does.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct Gestures {
+    /// A press shorter than this is a tap, a longer one a hold. Keep it below the recording
+    /// preroll so a held question loses none of its start.
+    pub tap_ms: u64,
+    /// The longest pause between the taps of a double or multiple press.
+    pub gap_ms: u64,
+    /// How long the button has to be held for a long hold.
+    pub long_hold_ms: u64,
+    /// When unset, a tap asks a question with hands-free recording and does nothing with
+    /// push-to-talk, where a tap is over before anything is said.
+    pub tap: Option<Action>,
+    pub double_press: Action,
+    /// Three or more taps.
+    pub multi_press: Action,
+    pub hold: Action,
+    /// Only reachable when `hold` does nothing, since a hold that asks a question records
+    /// until the button is let go.
+    pub long_hold: Action,
+}
+
+impl Default for Gestures {
+    fn default() -> Self {
+        Self {
+            tap_ms: 250,
+            gap_ms: 400,
+            long_hold_ms: 2000,
+            tap: None,
+            double_press: Action::Repeat,
+            multi_press: Action::NewConversation,
+            hold: Action::Ask,
+            long_hold: Action::Nothing,
+        }
+    }
+}
+
+impl Gestures {
+    pub fn tap(&self) -> Duration {
+        Duration::from_millis(self.tap_ms)
+    }
+
+    pub fn gap(&self) -> Duration {
+        Duration::from_millis(self.gap_ms)
+    }
+
+    pub fn long_hold(&self) -> Duration {
+        Duration::from_millis(self.long_hold_ms)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Nothing,
+    /// Record a question. In push-to-talk mode only a hold is long enough to say one.
+    Ask,
+    /// Play the last answer again.
+    Repeat,
+    /// Set the chat log aside and start over with an empty one.
+    NewConversation,
+}
+
+/// A light that shows what the toy is doing, so children can tell whether it is listening,
+/// thinking or broken. Every light configured here follows the same patterns.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Indicator {
+    /// LEDs under `/sys/class/leds`, by name, such as `ACT` for the Raspberry Pi's activity
+    /// LED. The LED's trigger is switched off while the toy runs.
+    pub leds: Vec<String>,
+    /// An LED wired to a GPIO line.
+    pub gpio: Option<OutputLine>,
+    /// Print each change of state, for trying out the indicator without a light.
+    pub log: bool,
+    pub patterns: Patterns,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OutputLine {
+    #[serde(default = "default_chip")]
+    pub chip: PathBuf,
+    pub line: u32,
+    /// Whether the LED lights when the line is low, as when it is wired between the pin and
+    /// the supply.
+    #[serde(default)]
+    pub active_low: bool,
+}
+
+fn default_chip() -> PathBuf {
+    Gpio::default().chip
+}
+
+/// How the light blinks in each state. A pattern is the lengths in milliseconds of
+/// alternating on and off periods, starting with on, played over and over. An empty pattern
+/// leaves the light off and a single length keeps it on.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct Patterns {
+    pub idle: Vec<u64>,
+    pub recording: Vec<u64>,
+    pub transcribing: Vec<u64>,
+    pub thinking: Vec<u64>,
+    pub speaking: Vec<u64>,
+    pub error: Vec<u64>,
+}
+
+impl Patterns {
+    /// Whether every step of every pattern lasts a while. A step that takes no time at all
+    /// would have the light switched on and off as fast as it can go.
+    fn check(&self) -> anyhow::Result<()> {
+        let patterns = [
+            ("idle", &self.idle),
+            ("recording", &self.recording),
+            ("transcribing", &self.transcribing),
+            ("thinking", &self.thinking),
+            ("speaking", &self.speaking),
+            ("error", &self.error),
+        ];
+        for (name, pattern) in patterns {
+            anyhow::ensure!(
+                !pattern.contains(&0),
+                "{name} has a step of 0 ms, every step must last at least 1 ms"
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Default for Patterns {
+    fn default() -> Self {
+        Self {
+            // a short flash now and then, to show the toy is on
+            idle: vec![60, 2940],
+            recording: vec![1],
+            transcribing: vec![120, 120],
+            thinking: vec![400, 400],
+            speaking: vec![900, 100],
+            error: vec![100, 100, 100, 100, 100, 700],
+        }
+    }
+}
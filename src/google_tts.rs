@@ -3,8 +3,11 @@ use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use crate::config::TtsEncoding;
+
 pub struct TtsClient {
     api_key: String,
+    encoding: TtsEncoding,
     client: Client,
 }
 
@@ -36,6 +39,7 @@ struct AudioConfig {
     audio_encoding: String,
     speaking_rate: f64,
     pitch: i32,
+    sample_rate_hertz: u32,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -45,9 +49,10 @@ struct SynthesizeResponse {
 }
 
 impl TtsClient {
-    pub fn new(api_key: &str) -> TtsClient {
+    pub fn new(api_key: &str, encoding: TtsEncoding) -> TtsClient {
         TtsClient {
             api_key: api_key.to_string(),
+            encoding,
             client: Client::new(),
         }
     }
@@ -66,9 +71,15 @@ impl TtsClient {
                 name: "en-US-Wavenet-A".to_string(),
             },
             audio_config: AudioConfig {
-                audio_encoding: "LINEAR16".to_string(),
+                audio_encoding: match self.encoding {
+                    TtsEncoding::Linear16 => "LINEAR16",
+                    TtsEncoding::Mp3 => "MP3",
+                    TtsEncoding::OggOpus => "OGG_OPUS",
+                }
+                .to_string(),
                 speaking_rate: 1.0,
                 pitch: 0,
+                sample_rate_hertz: self.encoding.sample_rate(),
             },
         };
 
@@ -87,7 +98,7 @@ impl TtsClient {
             anyhow::anyhow!("Failed to parse response. body: {pretty_body}\nerror: {e}")
         })?;
 
-        // Decode the Base64 string to get a Vec<u8> with the WAV data
+        // Decode the Base64 string to get a Vec<u8> with the encoded audio
         let audio = BASE64_STANDARD.decode(response.audio_content.as_bytes())?;
 
         Ok(audio)
@@ -0,0 +1,360 @@
+//! A light that follows what the toy is doing.
+
+use std::{fs, path::PathBuf, time::Duration};
+
+use anyhow::Context;
+use gpio_cdev::{Chip, LineHandle, LineRequestFlags};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::config;
+use crate::consts::PROJECT_NAME;
+
+/// Where the toy is in answering a question.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    /// Waiting for a question.
+    Idle,
+    Recording,
+    Transcribing,
+    /// Waiting on the chat model and speech synthesis.
+    Thinking,
+    Speaking,
+    /// The last question failed. Shown until the next one.
+    Error,
+}
+
+/// Shows the state on every configured light. The lights are driven by a task of their own,
+/// so changing the state never waits on them.
+pub struct Indicator {
+    state: watch::Sender<State>,
+    captions: watch::Sender<Captions>,
+}
+
+/// The last question and answer, for displays that can show text.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Captions {
+    /// who asked, when they have a button of their own
+    pub speaker: Option<String>,
+    pub question: String,
+    pub answer: String,
+}
+
+/// Follows the state and captions, for displays that can show more than a light can.
+/// Only the emulator has one so far.
+#[derive(Clone)]
+#[cfg_attr(not(feature = "emulate"), allow(dead_code))]
+pub struct Display {
+    pub state: watch::Receiver<State>,
+    pub captions: watch::Receiver<Captions>,
+}
+
+impl Indicator {
+    /// Take over the configured lights. This has to be called within the tokio runtime.
+    pub fn start(indicator: &config::Indicator) -> anyhow::Result<Self> {
+        let mut lights: Vec<Box<dyn Light>> = Vec::new();
+        for name in &indicator.leds {
+            lights.push(Box::new(SysfsLed::open(name)?));
+        }
+        if let Some(gpio) = &indicator.gpio {
+            lights.push(Box::new(GpioLed::open(gpio)?));
+        }
+        if indicator.log {
+            lights.push(Box::new(Logger));
+        }
+        let (state, states) = watch::channel(State::Idle);
+        if !lights.is_empty() {
+            tokio::spawn(drive(lights, patterns(&indicator.patterns), states));
+        }
+        let (captions, _) = watch::channel(Captions::default());
+        Ok(Self { state, captions })
+    }
+
+    pub fn display(&self) -> Display {
+        Display {
+            state: self.state.subscribe(),
+            captions: self.captions.subscribe(),
+        }
+    }
+
+    /// Show a new question, which clears the answer to the last one.
+    pub fn ask(&self, speaker: Option<&str>, question: &str) {
+        self.captions.send_replace(Captions {
+            speaker: speaker.map(str::to_owned),
+            question: question.to_owned(),
+            answer: String::new(),
+        });
+    }
+
+    pub fn answer(&self, answer: &str) {
+        self.captions
+            .send_modify(|captions| captions.answer = answer.to_owned());
+    }
+
+    pub fn set(&self, state: State) {
+        self.state.send_if_modified(|current| {
+            let changed = *current != state;
+            *current = state;
+            changed
+        });
+    }
+}
+
+/// Something that can show the state.
+trait Light: Send {
+    /// The state changed. Lights that can only be on or off leave this to the pattern.
+    fn show(&mut self, _state: State) {}
+
+    fn set(&mut self, on: bool) -> anyhow::Result<()>;
+}
+
+/// A steady or blinking light, as `(on, how long)` steps played over and over.
+type Steps = Vec<(bool, Duration)>;
+
+fn steps(pattern: &[u64]) -> Steps {
+    match pattern {
+        [] => vec![(false, Duration::MAX)],
+        [_] => vec![(true, Duration::MAX)],
+        _ => pattern
+            .iter()
+            .enumerate()
+            .map(|(i, &ms)| (i % 2 == 0, Duration::from_millis(ms)))
+            .collect(),
+    }
+}
+
+/// The steps for each state.
+struct Patterns {
+    idle: Steps,
+    recording: Steps,
+    transcribing: Steps,
+    thinking: Steps,
+    speaking: Steps,
+    error: Steps,
+}
+
+fn patterns(patterns: &config::Patterns) -> Patterns {
+    Patterns {
+        idle: steps(&patterns.idle),
+        recording: steps(&patterns.recording),
+        transcribing: steps(&patterns.transcribing),
+        thinking: steps(&patterns.thinking),
+        speaking: steps(&patterns.speaking),
+        error: steps(&patterns.error),
+    }
+}
+
+impl Patterns {
+    fn get(&self, state: State) -> &Steps {
+        match state {
+            State::Idle => &self.idle,
+            State::Recording => &self.recording,
+            State::Transcribing => &self.transcribing,
+            State::Thinking => &self.thinking,
+            State::Speaking => &self.speaking,
+            State::Error => &self.error,
+        }
+    }
+}
+
+/// Play the pattern of each state the indicator is put in, until the indicator is dropped.
+/// A light that fails is left alone from then on.
+async fn drive(
+    mut lights: Vec<Box<dyn Light>>,
+    patterns: Patterns,
+    mut states: watch::Receiver<State>,
+) {
+    loop {
+        let state = *states.borrow_and_update();
+        for light in &mut lights {
+            light.show(state);
+        }
+        'pattern: for &(on, length) in patterns.get(state).iter().cycle() {
+            lights.retain_mut(|light| match light.set(on) {
+                Ok(()) => true,
+                Err(e) => {
+                    eprintln!("Giving up on an indicator light: {e:#}");
+                    false
+                }
+            });
+            tokio::select! {
+                () = sleep(length) => {}
+                changed = states.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    break 'pattern;
+                }
+            }
+        }
+    }
+}
+
+/// Sleeps for `length`, where [`Duration::MAX`] means for good.
+async fn sleep(length: Duration) {
+    if length == Duration::MAX {
+        std::future::pending().await
+    } else {
+        tokio::time::sleep(length).await
+    }
+}
+
+/// An LED of the kernel's `leds` class. Its trigger is put back when the toy stops.
+struct SysfsLed {
+    dir: PathBuf,
+    max_brightness: String,
+    /// the trigger that was in charge of the LED before
+    trigger: String,
+}
+
+impl SysfsLed {
+    fn open(name: &str) -> anyhow::Result<Self> {
+        let dir = PathBuf::from("/sys/class/leds").join(name);
+        let read = |file: &str| {
+            fs::read_to_string(dir.join(file)).with_context(|| format!("can't read {dir:?}"))
+        };
+        let max_brightness = read("max_brightness")?.trim().to_owned();
+        // the file lists every trigger, with the current one in brackets
+        let triggers = read("trigger")?;
+        let trigger = triggers
+            .split_whitespace()
+            .find_map(|t| t.strip_prefix('[')?.strip_suffix(']'))
+            .unwrap_or("none")
+            .to_owned();
+        fs::write(dir.join("trigger"), "none")
+            .with_context(|| format!("can't take over {dir:?}, is it writable?"))?;
+        Ok(Self {
+            dir,
+            max_brightness,
+            trigger,
+        })
+    }
+}
+
+impl Light for SysfsLed {
+    fn set(&mut self, on: bool) -> anyhow::Result<()> {
+        let brightness = if on { &self.max_brightness } else { "0" };
+        fs::write(self.dir.join("brightness"), brightness)
+            .with_context(|| format!("can't set {:?}", self.dir))
+    }
+}
+
+impl Drop for SysfsLed {
+    fn drop(&mut self) {
+        let _ = fs::write(self.dir.join("trigger"), &self.trigger);
+    }
+}
+
+/// An LED on a GPIO line.
+struct GpioLed {
+    line: LineHandle,
+}
+
+impl GpioLed {
+    fn open(gpio: &config::OutputLine) -> anyhow::Result<Self> {
+        let mut chip =
+            Chip::new(&gpio.chip).with_context(|| format!("can't open {:?}", gpio.chip))?;
+        let mut flags = LineRequestFlags::OUTPUT;
+        if gpio.active_low {
+            flags |= LineRequestFlags::ACTIVE_LOW;
+        }
+        let line = chip
+            .get_line(gpio.line)
+            .and_then(|line| line.request(flags, 0, PROJECT_NAME))
+            .with_context(|| format!("can't request line {} of {:?}", gpio.line, gpio.chip))?;
+        Ok(Self { line })
+    }
+}
+
+impl Light for GpioLed {
+    fn set(&mut self, on: bool) -> anyhow::Result<()> {
+        Ok(self.line.set_value(on as u8)?)
+    }
+}
+
+/// Prints the state instead of showing it.
+struct Logger;
+
+impl Light for Logger {
+    fn show(&mut self, state: State) {
+        eprintln!("indicator: {state:?}");
+    }
+
+    fn set(&mut self, _on: bool) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[test]
+    fn patterns_alternate_starting_with_on() {
+        let ms = Duration::from_millis;
+        assert_eq!(steps(&[]), [(false, Duration::MAX)]);
+        assert_eq!(steps(&[1]), [(true, Duration::MAX)]);
+        assert_eq!(
+            steps(&[100, 200, 300, 400]),
+            [
+                (true, ms(100)),
+                (false, ms(200)),
+                (true, ms(300)),
+                (false, ms(400))
+            ]
+        );
+    }
+
+    /// What a light was told.
+    #[derive(Debug, PartialEq)]
+    enum Told {
+        Show(State),
+        Set(bool),
+    }
+
+    #[derive(Clone, Default)]
+    struct Witness(Arc<Mutex<Vec<Told>>>);
+
+    impl Light for Witness {
+        fn show(&mut self, state: State) {
+            self.0.lock().unwrap().push(Told::Show(state));
+        }
+
+        fn set(&mut self, on: bool) -> anyhow::Result<()> {
+            self.0.lock().unwrap().push(Told::Set(on));
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lights_follow_the_state() {
+        let light = Witness::default();
+        let (state, states) = watch::channel(State::Idle);
+        let patterns = patterns(&config::Patterns::default());
+        let driver = tokio::spawn(drive(vec![Box::new(light.clone())], patterns, states));
+        let (captions, _) = watch::channel(Captions::default());
+        let indicator = Indicator { state, captions };
+
+        // the idle pattern starts with a short flash
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        indicator.set(State::Recording);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(indicator);
+        driver.await.unwrap();
+
+        let told = light.0.lock().unwrap();
+        assert_eq!(
+            *told,
+            [
+                Told::Show(State::Idle),
+                Told::Set(true),
+                Told::Set(false),
+                Told::Show(State::Recording),
+                Told::Set(true),
+            ]
+        );
+    }
+}
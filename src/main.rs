@@ -1,22 +1,90 @@
+mod archive;
 mod audio;
 mod button;
 mod chatlog;
 mod config;
 mod consts;
 mod google_tts;
+mod indicator;
 mod openai;
 
-use audio::{play_wav, record_wav};
-use button::Button;
-use chatlog::{Author, LogMessage};
-use consts::{POLL_INTERVAL, SYSTEM_PROMPT};
+use audio::{Earcon, Earcons, Engine, Level, Playback, Player, Recorder, Sound, Trigger, WakeWord};
+use button::{Button, Recognizer};
+use chatlog::{Author, CutOff, LogMessage};
+use clap::{Args, Parser, Subcommand};
+use colored::Colorize;
+use config::{Action, RecordMode};
+use consts::{PROJECT_NAME, SYSTEM_PROMPT};
+use std::time::Instant;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::UnboundedReceiver;
 
 use google_tts::{Input::Ssml, TtsClient};
+use indicator::{Indicator, State};
 use openai::{ChatCompletionRequest, Message, OpenAIApiClient};
 
+/// A talking, teaching toy. Press the button and ask it something.
+#[derive(Parser)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Where recordings come from, `device` or `file:<wav>`. Overrides config.toml.
+    #[arg(long)]
+    source: Option<config::Source>,
+    /// Where sound goes, `device`, `null` or `file:<dir>`. Overrides config.toml.
+    #[arg(long)]
+    sink: Option<config::Sink>,
+    /// Answer a single question right away, without waiting for the button, then exit.
+    #[arg(long)]
+    once: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List every audio host and device, and the stream configs each supports.
+    Devices,
+    /// Show or change the playback level. Changes apply right away, even to a running toy.
+    Volume(VolumeArgs),
+    /// Record an example of the wake word for the spotter to compare against. Hold the button,
+    /// say "Hey Ushidashi" and let go.
+    Enroll,
+    /// Print every key event from every input device, to find the code a button sends and
+    /// the device it comes from.
+    Keys,
+}
+
+#[derive(Args)]
+struct VolumeArgs {
+    /// Master volume, from 0 to 100.
+    #[arg(value_parser = clap::value_parser!(u8).range(0..=100))]
+    percent: Option<u8>,
+    /// Whether to bring speech toward the loudness target.
+    #[arg(long)]
+    normalize: Option<bool>,
+    /// Loudness target for normalization, in dBFS.
+    #[arg(long, allow_hyphen_values = true)]
+    target_db: Option<f32>,
+    /// The most normalization may boost quiet speech by, in dB.
+    #[arg(long)]
+    max_gain_db: Option<f32>,
+    /// Limiter ceiling in dBFS. Peaks never go above it.
+    #[arg(long, allow_hyphen_values = true)]
+    ceiling_db: Option<f32>,
+}
+
 #[tokio::main]
 async fn main() {
-    match run().await {
+    let cli = Cli::parse();
+    let result = match cli.command {
+        None => run(cli.source, cli.sink, cli.once).await,
+        Some(Command::Devices) => audio::list_devices(),
+        Some(Command::Volume(args)) => adjust_level(args),
+        Some(Command::Enroll) => enroll(cli.source).await,
+        Some(Command::Keys) => {
+            config::Config::load().and_then(|config| button::print_keys(&config.button))
+        }
+    };
+    match result {
         Ok(()) => (),
         Err(e) => {
             eprintln!("Error: {:#?}", e);
@@ -25,37 +93,407 @@ async fn main() {
     }
 }
 
-async fn run() -> anyhow::Result<()> {
+async fn run(
+    source: Option<config::Source>,
+    sink: Option<config::Sink>,
+    once: bool,
+) -> anyhow::Result<()> {
     eprintln!("chatlog location: {:?}", chatlog::logfile()?);
 
     let secrets = config::Secrets::load()?;
+    let mut config = config::Config::load()?;
+    config.audio.source = source.unwrap_or(config.audio.source);
+    config.audio.sink = sink.unwrap_or(config.audio.sink);
 
-    let openai = OpenAIApiClient::new(&secrets.openai_api_key);
-    let tts = TtsClient::new(&secrets.google_tts_api_key);
+    let engine = Engine::start(&config.audio, &config.recording, &config.tts)?;
+    let recorder = Recorder::new(&config.audio, &engine)?;
+    let wake_word = if config.wake_word.enabled {
+        Some(WakeWord::load(
+            &config.wake_word,
+            &config.recording.vad,
+            recorder.sample_rate(),
+            recorder.channels(),
+        )?)
+    } else {
+        None
+    };
 
-    let button = Button::create();
+    let mut toy = Toy {
+        openai: OpenAIApiClient::new(&secrets.openai_api_key),
+        tts: TtsClient::new(&secrets.google_tts_api_key, config.tts.encoding),
+        recorder,
+        wake_word,
+        player: Player::new(&config.audio, &engine)?,
+        earcons: Earcons::load()?,
+        button: Button::create(&config.button)?,
+        last_answer: None,
+        indicator: Indicator::start(&config.indicator)?,
+        config,
+    };
+    toy.button.mirror(toy.indicator.display());
 
+    // the toy is dropped on the way out, which gives the indicator lights back
+    tokio::select! {
+        result = toy.serve(once) => result,
+        signal = stop_signal() => {
+            eprintln!("stopping on {signal}");
+            Ok(())
+        }
+    }
+}
+
+/// Wait for SIGINT or SIGTERM, which are how the toy is normally stopped.
+async fn stop_signal() -> &'static str {
+    let (Ok(mut interrupt), Ok(mut terminate)) = (
+        signal(SignalKind::interrupt()),
+        signal(SignalKind::terminate()),
+    ) else {
+        eprintln!("Can't catch signals, the indicator lights won't be given back on exit");
+        return std::future::pending().await;
+    };
+    tokio::select! {
+        _ = interrupt.recv() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    }
+}
+
+fn adjust_level(args: VolumeArgs) -> anyhow::Result<()> {
+    let mut level = Level::load()?;
+    let before = level.clone();
+    if let Some(percent) = args.percent {
+        level.volume = percent as f32 / 100.0;
+    }
+    level.normalize = args.normalize.unwrap_or(level.normalize);
+    level.target_db = args.target_db.unwrap_or(level.target_db);
+    level.max_gain_db = args.max_gain_db.unwrap_or(level.max_gain_db);
+    level.ceiling_db = args.ceiling_db.unwrap_or(level.ceiling_db);
+    if level != before {
+        level.store()?;
+    }
+    print!("{}", toml::to_string(&level)?);
+    Ok(())
+}
+
+/// Record an example of the wake word into the `wake_word` config directory.
+async fn enroll(source: Option<config::Source>) -> anyhow::Result<()> {
+    let mut config = config::Config::load()?;
+    config.audio.source = source.unwrap_or(config.audio.source);
+    let recording = config::Recording {
+        mode: config::RecordMode::PushToTalk,
+        ..config.recording
+    };
+    let engine = Engine::start(&config.audio, &recording, &config.tts)?;
+    let mut recorder = Recorder::new(&config.audio, &engine)?;
+    let mut button = Button::create(&config.button)?;
+
+    eprintln!("Hold the button, say \"Hey Ushidashi\" and let go.");
+    wait_for_press(&mut button).await?;
+    let recorded = recorder
+        .record(&mut button, &recording, Trigger::Button)
+        .await?;
+    if let Some(reason) = audio::rejection(&recorded, &recording) {
+        anyhow::bail!("{reason}, try again");
+    }
+    let clip = recorded.clip;
+
+    let base = xdg::BaseDirectories::with_prefix(PROJECT_NAME)?;
+    let name = chrono::Local::now().format("%Y%m%d-%H%M%S");
+    let path = base.place_config_file(format!("wake_word/{name}.wav"))?;
+    std::fs::write(&path, audio::to_wav(&clip)?)?;
+    let enrolled = base.list_config_files("wake_word").len();
+    eprintln!("saved {path:?}, {enrolled} examples enrolled");
+    Ok(())
+}
+
+/// Wait for the button to go down. A button that is already down has to be released first,
+/// so a stuck button can't start one recording after another.
+async fn wait_for_press(button: &mut Button) -> anyhow::Result<()> {
+    button.catch_up();
     loop {
-        while !button.pressed().ok_or(anyhow::anyhow!("button closed"))? {
-            std::thread::sleep(POLL_INTERVAL);
+        let event = button
+            .next()
+            .await
+            .ok_or(anyhow::anyhow!("button closed"))?;
+        if event.pressed {
+            return Ok(());
+        }
+    }
+}
+
+/// What the listener wants done next.
+enum Intent {
+    /// Record a question, from whoever's button it was if known.
+    Ask(Trigger, Option<String>),
+    Repeat,
+    NewConversation,
+}
+
+/// Everything needed to answer a question.
+struct Toy {
+    config: config::Config,
+    openai: OpenAIApiClient,
+    tts: TtsClient,
+    recorder: Recorder,
+    /// listens for the wake word between questions, if enabled
+    wake_word: Option<WakeWord>,
+    player: Player,
+    earcons: Earcons,
+    button: Button,
+    indicator: Indicator,
+    /// the speech of the last answer, for repeating it
+    last_answer: Option<Vec<u8>>,
+}
+
+impl Toy {
+    /// Wait for a gesture that has something to do or, if enabled, the wake word. As with
+    /// [`wait_for_press`], a button that is already down has to be released first.
+    async fn wait_for_intent(&mut self) -> anyhow::Result<Intent> {
+        let mut recognizer = Recognizer::new(&self.config.gestures);
+        // whoever last pressed a button of their own, since a tap is only recognized once it
+        // is over
+        let mut speaker = None;
+        // only what is heard from now on counts, since what came before may well be the toy's
+        // own answer
+        let mut heard = self.listen_for_wake_word();
+        self.button.catch_up();
+        let mut gesture = recognizer.update(self.button.pressed(), Instant::now());
+        loop {
+            if let Some(gesture) = gesture.take() {
+                match self
+                    .config
+                    .gestures
+                    .action(gesture, self.config.recording.mode)
+                {
+                    Action::Nothing => (),
+                    Action::Ask => return Ok(Intent::Ask(Trigger::Button, speaker)),
+                    Action::Repeat => return Ok(Intent::Repeat),
+                    Action::NewConversation => return Ok(Intent::NewConversation),
+                }
+            }
+            let deadline = recognizer.deadline();
+            tokio::select! {
+                event = self.button.next() => {
+                    let event = event.ok_or(anyhow::anyhow!("button closed"))?;
+                    if event.pressed {
+                        speaker = event.speaker.or(speaker);
+                    }
+                    gesture = recognizer.update(self.button.pressed(), Instant::now());
+                }
+                () = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now).into()),
+                    if deadline.is_some() =>
+                {
+                    gesture = recognizer.update(self.button.pressed(), Instant::now());
+                }
+                chunk = async { heard.as_mut()?.recv().await }, if heard.is_some() => {
+                    let Some(chunk) = chunk else {
+                        // a device that came back in another format stops listening, and
+                        // needs listening to afresh; any other source that stops leaves the
+                        // button
+                        let format = (self.recorder.sample_rate(), self.recorder.channels());
+                        heard = if self.wake_word.as_ref().is_some_and(|w| w.format() != format) {
+                            self.listen_for_wake_word()
+                        } else {
+                            None
+                        };
+                        continue;
+                    };
+                    if self.wake_word.as_mut().is_some_and(|wake_word| wake_word.push(&chunk)) {
+                        eprintln!("heard the wake word");
+                        return Ok(Intent::Ask(Trigger::WakeWord, None));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Start listening for the wake word, if enabled, in whatever format the source is in now.
+    fn listen_for_wake_word(&mut self) -> Option<UnboundedReceiver<Vec<f32>>> {
+        let wake_word = self.wake_word.as_mut()?;
+        wake_word.reset(self.recorder.sample_rate(), self.recorder.channels());
+        self.recorder.listen()
+    }
+
+    /// Answer questions until something goes wrong that can't be recovered from, or just
+    /// one right away with `once`.
+    async fn serve(&mut self, once: bool) -> anyhow::Result<()> {
+        if once {
+            return self.turn(Trigger::Button, None).await.map(|_| ());
+        }
+
+        // a press that interrupts playback goes straight into the next recording
+        let mut interrupted = false;
+        loop {
+            let intent = if interrupted {
+                let speaker = self.button.speaker().map(str::to_owned);
+                Intent::Ask(Trigger::Button, speaker)
+            } else {
+                self.wait_for_intent().await?
+            };
+            let result = match intent {
+                Intent::Ask(trigger, speaker) => self.turn(trigger, speaker).await,
+                Intent::Repeat => self.repeat().await,
+                Intent::NewConversation => {
+                    self.new_conversation().await.map(|()| Playback::Finished)
+                }
+            };
+            interrupted = match result {
+                Ok(playback) => {
+                    self.indicator.set(State::Idle);
+                    playback != Playback::Finished
+                }
+                Err(e) => {
+                    // a failed question shouldn't end the program, the next one may well work
+                    eprintln!("Error: {:#}", e);
+                    self.indicator.set(State::Error);
+                    self.earcon(Earcon::Error).await;
+                    false
+                }
+            };
+        }
+    }
+
+    /// Start playing an earcon. Earcons only help, so one that can't be played, say because
+    /// the speaker has been unplugged, is no reason to give up on a question.
+    fn start_earcon(&self, earcon: Earcon, looped: bool) -> Option<Sound> {
+        self.player
+            .start(self.earcons.get(earcon), looped)
+            .map_err(|e| eprintln!("Failed to play the {earcon:?} earcon: {e:#}"))
+            .ok()
+    }
+
+    /// Play an earcon to the end, if it can be played at all.
+    async fn earcon(&self, earcon: Earcon) {
+        let Some(sound) = self.start_earcon(earcon, false) else {
+            return;
+        };
+        if let Err(e) = sound.wait().await {
+            eprintln!("Failed to play the {earcon:?} earcon: {e:#}");
+        }
+    }
+
+    /// Play the last answer again. After a restart that is the last answer in the log,
+    /// spoken anew.
+    async fn repeat(&mut self) -> anyhow::Result<Playback> {
+        if self.last_answer.is_none() {
+            let last = chatlog::load_messages()?
+                .into_iter()
+                .rev()
+                .find(|message| matches!(message.author, Author::Bot));
+            if let Some(message) = last {
+                self.indicator.set(State::Thinking);
+                self.last_answer = Some(self.tts.synthesize(Ssml(message.text)).await?);
+            }
+        }
+        let Some(speech) = &self.last_answer else {
+            eprintln!("there is no answer to repeat");
+            self.earcon(Earcon::Rejected).await;
+            return Ok(Playback::Finished);
+        };
+        self.indicator.set(State::Speaking);
+        self.player.play(speech, &mut self.button).await
+    }
+
+    /// Set the chat log aside, so the next question starts over.
+    async fn new_conversation(&mut self) -> anyhow::Result<()> {
+        if let Some(old) = chatlog::new_conversation()? {
+            eprintln!("starting a new conversation, the last one is in {old:?}");
         }
-        let wav = record_wav(&button);
-        let text = openai.transcribe_audio(&wav).await?;
-        let next_message = get_response(&openai, &text).await?;
-        let wav = tts.synthesize(Ssml(next_message)).await?;
-        play_wav(&wav)?;
+        self.last_answer = None;
+        self.earcon(Earcon::Stopped).await;
+        Ok(())
+    }
+
+    /// Record a question, then think up and speak the answer.
+    async fn turn(
+        &mut self,
+        trigger: Trigger,
+        speaker: Option<String>,
+    ) -> anyhow::Result<Playback> {
+        self.indicator.set(State::Recording);
+        // the voice activity detector would take the chime for the question starting, so it
+        // is over before recording begins. A push-to-talk question is already being asked by
+        // the time the hold is recognized, and a chime then would only end up in it.
+        if trigger.mode(&self.config.recording) == RecordMode::HandsFree {
+            self.earcon(Earcon::Listening).await;
+        }
+        let recorded = self
+            .recorder
+            .record(&mut self.button, &self.config.recording, trigger)
+            .await?;
+        let stats = recorded.clip.stats();
+        if let Some(warning) = stats.warning() {
+            eprintln!("{}", warning.yellow());
+        }
+        if let Some(reason) = audio::rejection(&recorded, &self.config.recording) {
+            eprintln!("dropping recording: {reason}");
+            self.earcon(Earcon::Rejected).await;
+            // nothing was said, so there is nothing to have interrupted
+            return Ok(Playback::Finished);
+        }
+        self.earcon(Earcon::Stopped).await;
+
+        let clip = recorded.clip;
+
+        // losing the copy is no reason to lose the question
+        let audio_id = archive::store(&clip, &self.config.archive).unwrap_or_else(|e| {
+            eprintln!("Failed to archive the recording: {e:#}");
+            None
+        });
+
+        let upload = &self.config.upload;
+        let audio = audio::encode(
+            &clip.convert(upload.channels, upload.sample_rate),
+            upload.format,
+        )?;
+
+        let thinking = self.start_earcon(Earcon::Thinking, true);
+        self.indicator.set(State::Transcribing);
+        let text = self
+            .openai
+            .transcribe_audio(&audio.bytes, audio.file_name, audio.mime_type)
+            .await?;
+        self.indicator.ask(speaker.as_deref(), &text);
+        let question = LogMessage {
+            audio_id,
+            input: Some(stats),
+            speaker,
+            ..LogMessage::user(text)
+        };
+        self.indicator.set(State::Thinking);
+        let next_message = get_response(&self.openai, question).await?;
+        self.indicator.answer(&next_message);
+        // logged before it is spoken, so a failure to speak it doesn't leave the question
+        // unanswered in the history
+        chatlog::store_message(LogMessage::bot(next_message.clone()))?;
+        let speech = self.tts.synthesize(Ssml(next_message)).await?;
+        drop(thinking);
+
+        self.indicator.set(State::Speaking);
+        let playback = self.player.play(&speech, &mut self.button).await?;
+        self.last_answer = Some(speech);
+        if let Playback::Interrupted { played, total } = playback {
+            chatlog::cut_off_last(CutOff {
+                played_ms: played.as_millis() as u64,
+                total_ms: total.as_millis() as u64,
+            })?;
+        }
+        Ok(playback)
     }
 }
 
-async fn get_response(openai: &OpenAIApiClient, prompt: &str) -> anyhow::Result<String> {
+async fn get_response(
+    openai: &OpenAIApiClient,
+    mut question: LogMessage,
+) -> anyhow::Result<String> {
     // prefix the prompt with a timestamp
-    let prompt = format!("{}\n{}", chrono::Local::now(), prompt);
+    question.text = format!("{}\n{}", chrono::Local::now(), question.text);
+    let prompt = user_message(&question.text, question.speaker.as_deref());
 
     let mut messages = get_history()?;
 
-    chatlog::store_message(LogMessage::user(prompt.clone()))?;
+    chatlog::store_message(question)?;
 
-    messages.push(Message::user(prompt));
+    messages.push(prompt);
 
     let request = ChatCompletionRequest {
         model: "gpt-4".into(),
@@ -79,23 +517,39 @@ async fn get_response(openai: &OpenAIApiClient, prompt: &str) -> anyhow::Result<
         response.choices.len()
     );
 
-    let ret = response.choices.remove(0).message.content;
-
-    chatlog::store_message(LogMessage::bot(ret.clone()))?;
-
-    Ok(ret)
+    Ok(response.choices.remove(0).message.content)
 }
 
 /// load entire conversation history, including the system prompt
 fn get_history() -> anyhow::Result<Vec<Message>> {
     let mut ret = [Message::system(SYSTEM_PROMPT)].to_vec();
     for log_message in chatlog::load_messages()? {
-        let LogMessage { author, text } = log_message;
-        let message = match author {
-            Author::User => Message::user(text),
-            Author::Bot => Message::system(text),
+        let LogMessage {
+            author,
+            text,
+            cut_off,
+            audio_id: _,
+            input: _,
+            speaker,
+        } = log_message;
+        let message = match (author, cut_off) {
+            (Author::User, _) => user_message(&text, speaker.as_deref()),
+            (Author::Bot, None) => Message::system(text),
+            (Author::Bot, Some(cut_off)) => Message::system(format!(
+                "{text}\n(The listener interrupted this reply after hearing {:.1} of {:.1} seconds.)",
+                cut_off.played_ms as f64 / 1000.0,
+                cut_off.total_ms as f64 / 1000.0,
+            )),
         };
         ret.push(message);
     }
     Ok(ret)
 }
+
+/// A question for the chat model, naming who asked it when that is known.
+fn user_message(text: &str, speaker: Option<&str>) -> Message {
+    match speaker {
+        Some(speaker) => Message::user(format!("{speaker} asks:\n{text}")),
+        None => Message::user(text),
+    }
+}
@@ -132,11 +132,20 @@ impl OpenAIApiClient {
         }
     }
 
-    pub async fn transcribe_audio(&self, audio_data: &[u8]) -> anyhow::Result<String> {
+    /// Transcribe an audio file. Whisper goes by the file name to tell the format, so it
+    /// should have the right extension.
+    pub async fn transcribe_audio(
+        &self,
+        audio_data: &[u8],
+        file_name: &str,
+        mime_type: &str,
+    ) -> anyhow::Result<String> {
         let model = "whisper-1";
         let language = "en";
         let url = "https://api.openai.com/v1/audio/transcriptions";
-        let part = multipart::Part::bytes(audio_data.to_vec()).file_name("audio.wav");
+        let part = multipart::Part::bytes(audio_data.to_vec())
+            .file_name(file_name.to_string())
+            .mime_str(mime_type)?;
         let form = multipart::Form::new()
             .part("file", part)
             .text("model", model.to_string())